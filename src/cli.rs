@@ -1,5 +1,11 @@
+use std::path::PathBuf;
+
 use clap::{Parser, Subcommand};
 
+use crate::client::SourceMode;
+use crate::utils::protocol::Priority;
+use crate::worker::incremental::Incremental;
+
 #[derive(Parser)]
 #[command(name = "dbs")]
 #[command(version = "0.1.0")]
@@ -14,9 +20,13 @@ pub enum Commands {
     /// Start the controller node and build C files locally
     Build {
         /// C source files to compile
-        #[arg(required = true)]
+        #[arg(required_unless_present = "compdb", conflicts_with = "compdb")]
         files: Vec<String>,
 
+        /// Build the entries of a compilation database (compile_commands.json) instead
+        #[arg(long)]
+        compdb: Option<PathBuf>,
+
         /// Number of worker processes to spawn
         #[arg(short, long, default_value_t = 4)]
         workers: usize,
@@ -24,6 +34,42 @@ pub enum Commands {
         /// Server address for workers to connect to
         #[arg(short, long, default_value = "127.0.0.1:9000")]
         address: String,
+
+        /// Compiler executable to run on the workers (e.g. clang, arm-linux-gnueabihf-gcc)
+        #[arg(long, default_value = "gcc")]
+        cc: String,
+
+        /// Seconds a single compilation may run before it is killed
+        #[arg(long, default_value_t = 120)]
+        exec_timeout: u64,
+
+        /// Times a task is retried on another worker after its worker is lost
+        #[arg(long, default_value_t = 2)]
+        max_retries: u32,
+
+        /// Link the objects into this executable once they all compile
+        #[arg(long, group = "link")]
+        output: Option<PathBuf>,
+
+        /// Archive the objects into this static library once they all compile
+        #[arg(long, group = "link")]
+        static_lib: Option<PathBuf>,
+
+        /// Link the objects into this shared library once they all compile (adds -fPIC)
+        #[arg(long, group = "link")]
+        shared_lib: Option<PathBuf>,
+
+        /// Extra linker argument, e.g. `--link-arg=-lm`; may be repeated (not with --static-lib)
+        #[arg(long = "link-arg", allow_hyphen_values = true, conflicts_with = "static_lib")]
+        link_args: Vec<String>,
+
+        /// How to find objects that don't need rebuilding
+        #[arg(long, value_enum, default_value_t = Incremental::Mtime)]
+        incremental: Incremental,
+
+        /// Extra compiler arguments, e.g. `-- -O2 -DNDEBUG -I include/`
+        #[arg(last = true)]
+        cc_args: Vec<String>,
     },
 
     /// Start a server that accepts file submissions from clients
@@ -35,6 +81,46 @@ pub enum Commands {
         /// Server address to bind to
         #[arg(short, long, default_value = "127.0.0.1:9000")]
         address: String,
+
+        /// Compiler used for submissions that don't ask for one
+        #[arg(long, default_value = "gcc")]
+        cc: String,
+
+        /// Directory for the compilation cache, kept across restarts
+        #[arg(long, default_value = "dbs_cache")]
+        cache_dir: PathBuf,
+
+        /// Maximum size of the compilation cache in MiB
+        #[arg(long, default_value_t = 1024)]
+        cache_size: u64,
+
+        /// Compile every submission, never answer from the cache
+        #[arg(long)]
+        no_cache: bool,
+
+        /// Seconds a submission may wait for a free worker
+        #[arg(long, default_value_t = 300)]
+        queue_timeout: u64,
+
+        /// Seconds a single compilation may run before it is killed
+        #[arg(long, default_value_t = 120)]
+        exec_timeout: u64,
+
+        /// Times a task is retried on another worker after its worker is lost
+        #[arg(long, default_value_t = 2)]
+        max_retries: u32,
+
+        /// Certificate (PEM) to accept TLS connections with
+        #[arg(long, requires = "tls_key")]
+        tls_cert: Option<PathBuf>,
+
+        /// Private key (PEM) of --tls-cert
+        #[arg(long, requires = "tls_cert")]
+        tls_key: Option<PathBuf>,
+
+        /// Only let in remote workers with a certificate signed by this CA (PEM)
+        #[arg(long, requires = "tls_cert")]
+        tls_worker_ca: Option<PathBuf>,
     },
 
     /// Submit C files to a remote build server for compilation
@@ -46,12 +132,83 @@ pub enum Commands {
         /// Build server address
         #[arg(short, long, default_value = "127.0.0.1:9000")]
         server: String,
+
+        /// Compiler to use on the server (defaults to the server's choice)
+        #[arg(long)]
+        cc: Option<String>,
+
+        /// How included headers reach the worker
+        #[arg(long, value_enum, default_value_t = SourceMode::Raw)]
+        mode: SourceMode,
+
+        /// Scheduling priority on the server; equal priorities are served first come, first served
+        #[arg(long, value_enum, default_value_t = Priority::Normal)]
+        priority: Priority,
+
+        /// Maximum number of files sent to the server ahead of their results
+        #[arg(short, long, default_value_t = 16)]
+        jobs: usize,
+
+        /// Compile locally what the server can't: everything when it is unreachable, and files it rejects or times out
+        #[arg(long)]
+        local_fallback: bool,
+
+        /// With --local-fallback, also compile locally any file with no result after this many seconds
+        #[arg(long, requires = "local_fallback")]
+        latency_budget: Option<u64>,
+
+        /// Connect over TLS, trusting server certificates signed by this CA (PEM)
+        #[arg(long)]
+        tls_ca: Option<PathBuf>,
+
+        /// Extra compiler arguments, e.g. `-- -O2 -DNDEBUG -I include/`
+        #[arg(last = true)]
+        cc_args: Vec<String>,
     },
 
-    /// Start a worker node (internal use)
-    #[command(hide = true)]
+    /// Compile like the real compiler, on the build server when possible (for CC="dbs cc")
+    ///
+    /// Plain `-c` compiles of one C file go to $DBS_SERVER (default 127.0.0.1:9000) with
+    /// $DBS_CC (default gcc), preprocessed locally unless $DBS_MODE says otherwise, and over
+    /// TLS when $DBS_TLS_CA names the CA to trust. Anything else, or a compile the server
+    /// can't do, runs the real compiler here.
+    Cc {
+        /// Compiler arguments, exactly as they would be passed to gcc
+        #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
+        args: Vec<String>,
+    },
+
+    /// Start a worker node that joins a build server
     Worker {
-        /// Worker ID
-        id: String,
+        /// Worker ID (defaults to the process ID)
+        id: Option<String>,
+
+        /// Build server address to join
+        #[arg(short, long, default_value = "127.0.0.1:9000")]
+        server: String,
+
+        /// Additional compiler executable to offer, e.g. a cross toolchain (repeatable)
+        #[arg(long = "compiler")]
+        compilers: Vec<String>,
+
+        /// Send everything uncompressed (compression pays off on slow links, not on loopback)
+        #[arg(long)]
+        no_compression: bool,
+
+        /// Worker of `dbs build`: its tasks may force-include any file on this machine
+        #[arg(long, hide = true)]
+        local_build: bool,
+
+        /// Connect over TLS, trusting server certificates signed by this CA (PEM)
+        #[arg(long)]
+        tls_ca: Option<PathBuf>,
+
+        /// Cluster certificate (PEM) for servers that only let in certified workers
+        #[arg(long, requires_all = ["tls_ca", "tls_key"])]
+        tls_cert: Option<PathBuf>,
+
+        /// Private key (PEM) of --tls-cert
+        #[arg(long, requires = "tls_cert")]
+        tls_key: Option<PathBuf>,
     },
 }
@@ -0,0 +1,76 @@
+use std::path::Path;
+use std::process::Command;
+use std::sync::{Arc, Mutex, mpsc};
+use std::thread::{self, JoinHandle};
+
+use super::Event;
+
+// Compiles files on this machine when the server can't, a few at a time.
+pub struct LocalPool {
+    jobs: mpsc::Sender<(usize, String)>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl LocalPool {
+    // One thread per core, each reporting `Event::Local` on `events`.
+    pub fn start(compiler: &str, args: &[String], events: mpsc::Sender<Event>) -> Self {
+        let (jobs, queue) = mpsc::channel::<(usize, String)>();
+        let queue = Arc::new(Mutex::new(queue));
+        let count = thread::available_parallelism().map(|n| n.get()).unwrap_or(4);
+
+        let threads = (0..count)
+            .map(|_| {
+                let queue = Arc::clone(&queue);
+                let events = events.clone();
+                let compiler = compiler.to_string();
+                let args = args.to_vec();
+                thread::spawn(move || {
+                    loop {
+                        // Hold the lock only while taking a job
+                        let job = queue.lock().unwrap().recv();
+                        let Ok((index, path)) = job else {
+                            break;
+                        };
+                        let result = compile(&compiler, &args, &path);
+                        events.send(Event::Local(index, result)).ok();
+                    }
+                })
+            })
+            .collect();
+
+        Self { jobs, threads }
+    }
+
+    pub fn compile(&self, index: usize, path: &str) {
+        self.jobs.send((index, path.to_string())).ok();
+    }
+
+    // Let queued compiles finish and stop the threads.
+    pub fn finish(self) {
+        drop(self.jobs);
+        for handle in self.threads {
+            handle.join().ok();
+        }
+    }
+}
+
+// <cc> <args> -c file.c -o file.o, next to the source like a server result
+fn compile(compiler: &str, args: &[String], path: &str) -> Result<(), String> {
+    let output = Command::new(compiler)
+        .args(args)
+        .arg("-c")
+        .arg(path)
+        .arg("-o")
+        .arg(Path::new(path).with_extension("o"))
+        .output()
+        .map_err(|e| format!("Failed to run {}: {}", compiler, e))?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(format!(
+            "{}{}",
+            String::from_utf8_lossy(&output.stdout),
+            String::from_utf8_lossy(&output.stderr)
+        ))
+    }
+}
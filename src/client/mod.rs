@@ -1,52 +1,489 @@
-use std::fs;
-use std::io::{self, Write};
-use std::net::TcpStream;
-use std::path::Path;
-use std::sync::{Arc, Mutex};
+mod local;
+mod source;
+mod wrapper;
+
+use std::fs::{self, File};
+use std::io::{self, Seek, SeekFrom};
+use std::net::Shutdown;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, mpsc};
 use std::thread;
+use std::time::{Duration, Instant};
+
+use local::LocalPool;
+pub use source::SourceMode;
+pub use wrapper::run_cc;
+use source::prepare_source;
+
+use crate::utils::flags::prepare_args;
+use crate::utils::net::{self, Connection};
+use crate::utils::protocol::{
+    BatchEnd, BatchFile, BatchResult, FileResult, Message, OpCode, Payload, Priority, Resume, Status,
+    SubmitBatch, SubmitFile, Submission, begin_body, is_integrity_error, part_path, read_body, receive_body, send_bytes,
+    verify_body,
+};
+
+// How `submit_files` compiles its files.
+pub struct SubmitOptions {
+    // None leaves the choice to the server
+    pub compiler: Option<String>,
+    // Passed to the compiler for every file
+    pub cc_args: Vec<String>,
+    // How headers are shipped
+    pub mode: SourceMode,
+    // How the server schedules the files against other work
+    pub priority: Priority,
+    // Files sent ahead of their results
+    pub max_in_flight: usize,
+    // Compile here whatever the server can't: everything when it is
+    // unreachable, and files it rejects or times out
+    pub local_fallback: bool,
+    // With `local_fallback`, also compile here any file whose result takes
+    // longer than this
+    pub latency_budget: Option<Duration>,
+}
+
+// Why the server didn't produce an object: its status and log.
+type NotCompiled = (Status, String);
+
+// What the threads working on a batch report back.
+enum Event {
+    // The file couldn't be prepared, so it was never sent
+    NotSent(usize, String),
+    Sent(usize),
+    // A BatchResult, with its object received into the `.part` file, or
+    // why not: the log, or a failure to write the object
+    Result(BatchResult, Result<(), String>),
+    // The server ended the batch, or the connection failed
+    RemoteDone(io::Result<()>),
+    // A local compile finished
+    Local(usize, Result<(), String>),
+}
+
+// Where each file stands.
+#[derive(Clone)]
+enum FileState {
+    Waiting,
+    Sent(Instant),
+    // Handed to the local compiler, for the given reason
+    Local(String),
+    OnServer(bool),
+    Locally(bool, String),
+    NotSent,
+}
 
-use crate::utils::protocol::{Message, OpCode};
+// How often the batch loop looks for files over the latency budget.
+const BUDGET_CHECK: Duration = Duration::from_millis(100);
+
+// How many times the rest of an object is fetched again after its
+// transfer broke off, or all of it after it arrived corrupted.
+const RESUME_ATTEMPTS: u32 = 3;
+
+// How many times a source is sent before its corruption on the way to the
+// server counts as a failure.
+const SEND_ATTEMPTS: u32 = 3;
 
 // Client that submits files to server for compilation
-pub fn submit_files(files: Vec<String>, server_addr: &str) -> io::Result<()> {
+pub fn submit_files(files: Vec<String>, server_addr: &str, options: SubmitOptions) -> io::Result<()> {
+    let cc_args = prepare_args(&options.cc_args).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let files = Arc::new(files);
+    let (events, incoming) = mpsc::channel();
+    
+    // Preprocessing, header scans and fallback compiles run here; without
+    // --cc assume gcc
+    let local_compiler = options.compiler.as_deref().unwrap_or("gcc");
+    let local = options
+        .local_fallback
+        .then(|| LocalPool::start(local_compiler, &cc_args, events.clone()));
+    
     println!("[Client] Connecting to build server at {}", server_addr);
-    println!("[Client] Submitting {} files in parallel...", files.len());
+    // Sources the server got corrupted go back to the sender until the
+    // batch is done
+    let (resend, to_resend) = mpsc::channel();
+    let mut resend = Some(resend);
+    let batch = net::connect(server_addr).and_then(|stream| {
+        start_batch(stream, server_addr, &files, &cc_args, &options, events.clone(), to_resend)
+    });
+    let mut progress = Progress::new(Arc::clone(&files), options.local_fallback);
+    let mut actions = Vec::new();
+    let batch = match batch {
+        Ok(batch) => Some(batch),
+        Err(e) => {
+            actions = progress.unreachable(e);
+            None
+        }
+    };
     
-    let results: Arc<Mutex<Vec<(String, bool)>>> = Arc::new(Mutex::new(Vec::new()));
-    let mut handles = Vec::new();
+    loop {
+        for action in actions.drain(..) {
+            match action {
+                Action::CompileLocally(index) => {
+                    if let Some(pool) = &local {
+                        pool.compile(index, &files[index]);
+                    }
+                }
+                // A failed send means the connection is going down, which
+                // the result reader reports
+                Action::Resend(index) => {
+                    if let Some(resend) = &resend {
+                        resend.send(index).ok();
+                    }
+                }
+            }
+        }
+        if progress.finished() {
+            break;
+        }
+        if progress.done() {
+            resend = None;
+        }
+        actions = match incoming.recv_timeout(BUDGET_CHECK) {
+            Ok(event) => {
+                if let (Event::Result(..), Some((_, slot_freed))) = (&event, &batch) {
+                    slot_freed.try_recv().ok();
+                }
+                progress.handle(event, resend.is_some())
+            }
+            Err(_) => options
+                .latency_budget
+                .map(|budget| progress.over_budget(budget))
+                .unwrap_or_default(),
+        };
+    }
+    let Progress { state, remote_error, .. } = progress;
     
-    for file_path in files {
-        let server_addr = server_addr.to_string();
-        let results_clone = Arc::clone(&results);
-        
-        let handle = thread::spawn(move || {
-            if let Err(e) = submit_single_file(&file_path, &server_addr) {
-                eprintln!("[Client] Error submitting {}: {}", file_path, e);
-                let mut r = results_clone.lock().unwrap();
-                r.push((file_path, false));
-            } else {
-                let mut r = results_clone.lock().unwrap();
-                r.push((file_path, true));
+    // Stop whatever is still running for the batch
+    if let Some((stream, slot_freed)) = batch {
+        drop(slot_freed);
+        stream.shutdown(Shutdown::Both).ok();
+    }
+    if let Some(pool) = local {
+        pool.finish();
+    }
+    
+    // Report summary
+    let on_server = state.iter().filter(|s| matches!(s, FileState::OnServer(true))).count();
+    let locally = state.iter().filter(|s| matches!(s, FileState::Locally(true, _))).count();
+    println!(
+        "\n[Client] Submission complete: {}/{} files succeeded ({} on the server, {} locally).",
+        on_server + locally,
+        files.len(),
+        on_server,
+        locally
+    );
+    for (file_path, file_state) in files.iter().zip(&state) {
+        if let FileState::Locally(ok, reason) = file_state {
+            let outcome = if *ok { "compiled" } else { "FAILED" };
+            println!("[Client]   {} locally: {} ({})", outcome, file_path, reason);
+        }
+    }
+    
+    match remote_error {
+        Some(e) if !options.local_fallback => Err(e),
+        _ => Ok(()),
+    }
+}
+
+// Send the manifest and start the threads that feed the batch to the server
+// and read its results. After every file, the sender sends the ones that
+// come in on `to_resend` until it closes. Returns the connection and the
+// receiver that frees a slot for the sender whenever a result comes in.
+fn start_batch(
+    mut stream: Connection,
+    server_addr: &str,
+    files: &Arc<Vec<String>>,
+    cc_args: &[String],
+    options: &SubmitOptions,
+    events: mpsc::Sender<Event>,
+    to_resend: mpsc::Receiver<usize>,
+) -> io::Result<(Connection, mpsc::Receiver<()>)> {
+    println!(
+        "[Client] Submitting {} files, up to {} at a time...",
+        files.len(),
+        options.max_in_flight
+    );
+    
+    let manifest = SubmitBatch {
+        priority: options.priority,
+        names: files
+            .iter()
+            .map(|file_path| Path::new(file_path).file_name().unwrap_or_default().to_string_lossy().to_string())
+            .collect(),
+    };
+    manifest.send(&mut stream)?;
+    
+    // Sources go out on their own thread while another collects results.
+    // Every file sent takes a slot in `in_flight`; every result frees one.
+    let (in_flight, slot_freed) = mpsc::sync_channel::<()>(options.max_in_flight.max(1));
+    {
+        let mut stream = stream.try_clone()?;
+        let files = Arc::clone(files);
+        let compiler = options.compiler.clone().unwrap_or_default();
+        let cc_args = cc_args.to_vec();
+        let mode = options.mode;
+        let events = events.clone();
+        thread::spawn(move || {
+            for index in (0..files.len()).chain(to_resend) {
+                let file_path = &files[index];
+                println!("[Client] Submitting {}...", file_path);
+                let (submission, contents) = match prepare_submission(file_path, &compiler, &cc_args, mode) {
+                    Ok(prepared) => prepared,
+                    Err(e) => {
+                        events.send(Event::NotSent(index, e.to_string())).ok();
+                        continue;
+                    }
+                };
+                
+                let file = BatchFile {
+                    index: index as u32,
+                    submission,
+                };
+                if in_flight.send(()).is_err() {
+                    return;
+                }
+                events.send(Event::Sent(index)).ok();
+                if file.send(&mut stream).and_then(|_| send_bytes(&mut stream, &contents)).is_err() {
+                    // The reader sees the connection fail and reports it
+                    stream.shutdown(Shutdown::Both).ok();
+                    return;
+                }
+            }
+            if (BatchEnd { error: None }).send(&mut stream).is_err() {
+                stream.shutdown(Shutdown::Both).ok();
             }
         });
-        
-        handles.push(handle);
     }
     
-    // Wait for all submissions to complete
-    for handle in handles {
-        handle.join().unwrap();
+    // Results arrive in whatever order the files finish
+    {
+        let mut stream = stream.try_clone()?;
+        let server_addr = server_addr.to_string();
+        let files = Arc::clone(files);
+        thread::spawn(move || {
+            let result = loop {
+                let msg = match Message::read(&mut stream) {
+                    Ok(msg) => msg,
+                    Err(e) => break Err(e),
+                };
+                match msg.op {
+                    OpCode::BatchResult => {
+                        let result = match BatchResult::from_message(&msg) {
+                            Ok(result) => result,
+                            Err(e) => break Err(e),
+                        };
+                        let Some(file_path) = files.get(result.index as usize) else {
+                            let error = format!("Result for unknown file #{}", result.index);
+                            break Err(io::Error::new(io::ErrorKind::InvalidData, error));
+                        };
+                        let received = if result.result.status == Status::Succeeded {
+                            receive_object(&mut stream, &server_addr, &part_path(&object_path(file_path)))
+                        } else {
+                            read_body(&mut stream).map(|log| Err(String::from_utf8_lossy(&log).to_string()))
+                        };
+                        match received {
+                            Ok(received) => {
+                                if events.send(Event::Result(result, received)).is_err() {
+                                    return;
+                                }
+                            }
+                            Err(e) => break Err(e),
+                        }
+                    }
+                    OpCode::BatchEnd => match BatchEnd::from_message(&msg) {
+                        Ok(BatchEnd { error: None }) => break Ok(()),
+                        // The server refused the whole batch
+                        Ok(BatchEnd { error: Some(reason) }) => break Err(io::Error::other(reason)),
+                        Err(e) => break Err(e),
+                    },
+                    _ => break Err(io::Error::new(io::ErrorKind::InvalidData, "Unexpected response from server")),
+                }
+            };
+            events.send(Event::RemoteDone(result)).ok();
+        });
+    }
+    
+    Ok((stream, slot_freed))
+}
+
+// What the batch loop does next for a file.
+#[derive(Debug, PartialEq)]
+enum Action {
+    CompileLocally(usize),
+    // Send it to the server again
+    Resend(usize),
+}
+
+// Where the files of a batch stand. Decides what happens to each file as
+// events come in; the batch loop carries out the actions it returns.
+struct Progress {
+    files: Arc<Vec<String>>,
+    state: Vec<FileState>,
+    // Times each file was sent
+    sends: Vec<u32>,
+    // Results the server still owes
+    awaiting: usize,
+    remote_open: bool,
+    remote_error: Option<io::Error>,
+    // Compile here what the server can't
+    local_fallback: bool,
+}
+
+impl Progress {
+    fn new(files: Arc<Vec<String>>, local_fallback: bool) -> Self {
+        Self {
+            state: vec![FileState::Waiting; files.len()],
+            sends: vec![0; files.len()],
+            files,
+            awaiting: 0,
+            remote_open: true,
+            remote_error: None,
+            local_fallback,
+        }
+    }
+    
+    // Every file has its outcome.
+    fn done(&self) -> bool {
+        self.state
+            .iter()
+            .all(|s| matches!(s, FileState::OnServer(_) | FileState::Locally(..) | FileState::NotSent))
     }
     
-    // Report summary
-    let final_results = results.lock().unwrap();
-    let success_count = final_results.iter().filter(|(_, success)| *success).count();
-    println!("\n[Client] Submission complete: {}/{} files succeeded.", success_count, final_results.len());
+    // Nothing left to wait for. Once everything is done, the server still
+    // closes the batch, unless it owes results we took over locally.
+    fn finished(&self) -> bool {
+        self.done() && (!self.remote_open || self.awaiting > 0)
+    }
+    
+    fn unreachable(&mut self, e: io::Error) -> Vec<Action> {
+        self.remote_open = false;
+        let actions = self.lose_remaining(&format!("server unreachable: {}", e));
+        self.remote_error = Some(e);
+        actions
+    }
     
-    Ok(())
+    // Files sent longer than `budget` ago without a result.
+    fn over_budget(&mut self, budget: Duration) -> Vec<Action> {
+        if !self.local_fallback {
+            return Vec::new();
+        }
+        let mut actions = Vec::new();
+        for (index, file_state) in self.state.iter_mut().enumerate() {
+            if let FileState::Sent(at) = file_state
+                && at.elapsed() > budget
+            {
+                *file_state = FileState::Local(format!("no result within {} s", budget.as_secs_f32()));
+                actions.push(Action::CompileLocally(index));
+            }
+        }
+        actions
+    }
+    
+    // `can_resend` says whether sources the server got corrupted can still
+    // go out again.
+    fn handle(&mut self, event: Event, can_resend: bool) -> Vec<Action> {
+        let files = Arc::clone(&self.files);
+        match event {
+            Event::NotSent(index, e) => {
+                eprintln!("[Client] Error submitting {}: {}", files[index], e);
+                self.state[index] = FileState::NotSent;
+            }
+            Event::Sent(index) => {
+                self.state[index] = FileState::Sent(Instant::now());
+                self.sends[index] += 1;
+                self.awaiting += 1;
+            }
+            Event::Result(result, received) => {
+                self.awaiting -= 1;
+                let index = result.index as usize;
+                // Taken over locally: the local compile's result counts,
+                // even when this one got here first
+                if !matches!(self.state[index], FileState::Sent(_)) {
+                    fs::remove_file(part_path(&object_path(&files[index]))).ok();
+                    return Vec::new();
+                }
+                if result.result.status == Status::Corrupted && self.sends[index] < SEND_ATTEMPTS && can_resend {
+                    eprintln!("[Client] {} arrived corrupted at the server, sending it again", files[index]);
+                    return vec![Action::Resend(index)];
+                }
+                match handle_batch_result(result, received, &files) {
+                    Ok(()) => self.state[index] = FileState::OnServer(true),
+                    Err((status, log)) => {
+                        let reason = match status {
+                            Status::Rejected => format!("rejected by the server: {}", log.trim_end()),
+                            Status::TimedOut => "timed out on the server".to_string(),
+                            Status::Corrupted => format!("corrupted on the way to the server: {}", log.trim_end()),
+                            // The compiler's own verdict; it would say the same here
+                            _ => {
+                                self.state[index] = FileState::OnServer(false);
+                                return Vec::new();
+                            }
+                        };
+                        if !self.local_fallback {
+                            self.state[index] = FileState::OnServer(false);
+                            return Vec::new();
+                        }
+                        self.state[index] = FileState::Local(reason);
+                        return vec![Action::CompileLocally(index)];
+                    }
+                }
+            }
+            Event::RemoteDone(result) => {
+                self.remote_open = false;
+                self.awaiting = 0;
+                let reason = match result {
+                    Ok(()) => "the server ended the batch early".to_string(),
+                    // Every result is in; only the closing BatchEnd was lost
+                    Err(_) if self.done() => return Vec::new(),
+                    Err(e) => {
+                        let reason = format!("connection to the server failed: {}", e);
+                        self.remote_error = Some(e);
+                        reason
+                    }
+                };
+                return self.lose_remaining(&reason);
+            }
+            Event::Local(index, result) => {
+                let FileState::Local(reason) = &self.state[index] else {
+                    return Vec::new();
+                };
+                match &result {
+                    Ok(()) => println!("[Client] Compiled {} locally ({})", files[index], reason),
+                    Err(log) => eprintln!("[Client] Local compilation failed for {}: {}", files[index], log),
+                }
+                self.state[index] = FileState::Locally(result.is_ok(), reason.clone());
+            }
+        }
+        Vec::new()
+    }
+    
+    // The server won't compile the files it hasn't answered for `reason`:
+    // hand them to the local compiler, or give up on them.
+    fn lose_remaining(&mut self, reason: &str) -> Vec<Action> {
+        let mut actions = Vec::new();
+        for (index, file_state) in self.state.iter_mut().enumerate() {
+            if !matches!(file_state, FileState::Waiting | FileState::Sent(_)) {
+                continue;
+            }
+            if self.local_fallback {
+                *file_state = FileState::Local(reason.to_string());
+                actions.push(Action::CompileLocally(index));
+            } else {
+                eprintln!("[Client] Not compiled: {} ({})", self.files[index], reason);
+                *file_state = FileState::OnServer(false);
+            }
+        }
+        actions
+    }
 }
 
-fn submit_single_file(file_path: &str, server_addr: &str) -> io::Result<()> {
+// Read a source and everything it needs from disk. Returns the submission
+// and the source to send after it.
+fn prepare_submission(
+    file_path: &str,
+    compiler: &str,
+    cc_args: &[String],
+    mode: SourceMode,
+) -> io::Result<(Submission, Vec<u8>)> {
     let path = Path::new(file_path);
     
     if !path.exists() {
@@ -57,56 +494,405 @@ fn submit_single_file(file_path: &str, server_addr: &str) -> io::Result<()> {
         return Err(io::Error::new(io::ErrorKind::InvalidInput, "Not a .c file"));
     }
     
-    println!("[Client] Submitting {}...", file_path);
-    
-    // Create connection for this file
-    let mut stream = TcpStream::connect(server_addr)?;
+    // Preprocessing and header scans run locally; without --cc assume gcc
+    let local_compiler = if compiler.is_empty() { "gcc" } else { compiler };
+    let source = prepare_source(file_path, mode, local_compiler, cc_args)?;
     
-    // Read file contents
-    let file_contents = fs::read(file_path)?;
-    let filename = path.file_name()
-        .and_then(|n| n.to_str())
-        .unwrap_or("unknown.c");
-    
-    // Create payload: [filename_len (4 bytes)][filename][file_contents]
-    let mut payload = Vec::new();
-    let filename_bytes = filename.as_bytes();
-    payload.extend_from_slice(&(filename_bytes.len() as u32).to_be_bytes());
-    payload.extend_from_slice(filename_bytes);
-    payload.extend_from_slice(&file_contents);
+    let submission = Submission {
+        filename: source.filename,
+        compiler: compiler.to_string(),
+        args: source.args,
+        origin: source.origin,
+        headers: source.headers,
+    };
+    Ok((submission, source.contents))
+}
+
+// Compile a single file on the server and write its object to `output`.
+// Returns the status and log when it didn't compile.
+pub fn compile_one(
+    file_path: &str,
+    server_addr: &str,
+    compiler: &str,
+    cc_args: &[String],
+    mode: SourceMode,
+    output: &Path,
+) -> io::Result<Result<(), NotCompiled>> {
+    let (submission, contents) = prepare_submission(file_path, compiler, cc_args, mode)?;
+    let mut sends = 0;
+    let (mut stream, result) = loop {
+        let mut stream = net::connect(server_addr)?;
+        SubmitFile {
+            priority: Priority::Normal,
+            submission: submission.clone(),
+        }
+        .send(&mut stream)?;
+        send_bytes(&mut stream, &contents)?;
+        sends += 1;
+        
+        let result = FileResult::from_message(&Message::read(&mut stream)?)?;
+        if result.status != Status::Corrupted || sends == SEND_ATTEMPTS {
+            break (stream, result);
+        }
+        read_body(&mut stream)?;
+        eprintln!("[Client] {} arrived corrupted at {}, sending it again", file_path, server_addr);
+    };
+    if result.status != Status::Succeeded {
+        let log = read_body(&mut stream)?;
+        return Ok(Err((result.status, String::from_utf8_lossy(&log).to_string())));
+    }
+    let part = part_path(output);
+    let written = receive_object(&mut stream, server_addr, &part)?.and_then(|_| {
+        fs::rename(&part, output).map_err(|e| format!("Failed to write {}: {}", output.display(), e))
+    });
+    Ok(written.map_err(|e| (Status::Failed, e)))
+}
+
+// Where the object of a submitted file is written.
+fn object_path(file_path: &str) -> PathBuf {
+    Path::new(file_path).with_extension("o")
+}
+
+// Receive the object that follows a successful result into `part`. If the
+// connection breaks halfway, the rest is fetched from `server_addr` over a
+// new connection. The inner error means `part` couldn't be written.
+fn receive_object(stream: &mut Connection, server_addr: &str, part: &Path) -> io::Result<Result<(), String>> {
+    let begin = begin_body(stream)?;
+    let write_error = |e: io::Error| format!("Failed to write {}: {}", part.display(), e);
+    let mut file = match File::create(part) {
+        Ok(file) => file,
+        Err(e) => {
+            receive_body(stream, &begin, &mut io::sink())??;
+            return Ok(Err(write_error(e)));
+        }
+    };
     
-    // Send SubmitFile message
-    let msg = Message::new(OpCode::SubmitFile, payload);
-    stream.write_all(&msg.serialize())?;
+    let mut received = receive_body(stream, &begin, &mut file);
+    let mut attempts = 0;
+    loop {
+        match received {
+            // Read back what was written, so a bad disk is caught as well
+            Ok(Ok(_)) => match File::open(part).and_then(|mut written| verify_body(&begin, &mut written)) {
+                Ok(()) => return Ok(Ok(())),
+                Err(e) if is_integrity_error(&e) && !begin.transfer.is_empty() && attempts < RESUME_ATTEMPTS => {
+                    attempts += 1;
+                    eprintln!("[Client] {}: {}, fetching it again", part.display(), e);
+                    file.set_len(0)?;
+                    file.seek(SeekFrom::Start(0))?;
+                    received = resume(server_addr, &begin.transfer, 0, &mut file);
+                }
+                Err(e) => {
+                    fs::remove_file(part).ok();
+                    return Ok(Err(format!("Received {}: {}", part.display(), e)));
+                }
+            },
+            Ok(Err(e)) => {
+                fs::remove_file(part).ok();
+                return Ok(Err(write_error(e)));
+            }
+            Err(e) if begin.transfer.is_empty() || attempts == RESUME_ATTEMPTS => {
+                fs::remove_file(part).ok();
+                return Err(e);
+            }
+            Err(e) => {
+                attempts += 1;
+                let offset = begin.offset + file.metadata()?.len();
+                eprintln!(
+                    "[Client] Transfer of {} broke off at {} of {} bytes ({}), resuming",
+                    part.display(),
+                    offset,
+                    begin.size,
+                    e
+                );
+                received = resume(server_addr, &begin.transfer, offset, &mut file);
+            }
+        }
+    }
+}
+
+// Fetch the rest of a transfer, from `offset` on, into `file`.
+fn resume(server_addr: &str, transfer: &str, offset: u64, file: &mut File) -> io::Result<io::Result<u64>> {
+    let mut stream = net::connect(server_addr)?;
+    Resume {
+        transfer: transfer.to_string(),
+        offset,
+    }
+    .send(&mut stream)?;
     
-    // Wait for FileResult
-    let result = Message::read(&mut stream)?;
+    let reply = FileResult::from_message(&Message::read(&mut stream)?)?;
+    if reply.status != Status::Succeeded {
+        let reason = read_body(&mut stream)?;
+        return Err(io::Error::other(String::from_utf8_lossy(&reason).to_string()));
+    }
+    let begin = begin_body(&mut stream)?;
+    if begin.offset != offset {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("transfer resumed at {} instead of {}", begin.offset, offset),
+        ));
+    }
+    receive_body(&mut stream, &begin, file)
+}
+
+// Handle one BatchResult whose object, if any, is in the file's `.part`
+// file: move it into place, or return the status and log.
+fn handle_batch_result(result: BatchResult, received: Result<(), String>, files: &[String]) -> Result<(), NotCompiled> {
+    let file_path = &files[result.index as usize];
+    let FileResult { status, name: returned_filename } = result.result;
     
-    if result.op == OpCode::FileResult {
-        // Parse: [1 byte success][4 bytes filename_len][filename][.o file contents or error msg]
-        if result.payload.is_empty() {
-            return Err(io::Error::new(io::ErrorKind::InvalidData, "Empty response from server"));
+    match (status, received) {
+        (Status::Succeeded, Ok(())) => {
+            // Save .o file next to its source
+            let output_path = object_path(file_path);
+            match fs::rename(part_path(&output_path), &output_path) {
+                Ok(()) => {
+                    println!("[Client] Received: {} -> {}", returned_filename, output_path.display());
+                    Ok(())
+                }
+                Err(e) => {
+                    let error_msg = format!("Failed to write {}: {}", output_path.display(), e);
+                    eprintln!("[Client] {}", error_msg);
+                    Err((Status::Failed, error_msg))
+                }
+            }
         }
-        
-        let success = result.payload[0] == 1;
-        let filename_len = u32::from_be_bytes(result.payload[1..5].try_into().unwrap()) as usize;
-        let returned_filename = String::from_utf8_lossy(&result.payload[5..5+filename_len]).to_string();
-        let data = &result.payload[5+filename_len..];
-        
-        if success {
-            // Save .o file
-            let output_path = file_path.replace(".c", ".o");
-            fs::write(&output_path, data)?;
-            println!("[Client] Received: {} -> {}", returned_filename, output_path);
-        } else {
-            // Error message
-            let error_msg = String::from_utf8_lossy(data);
-            eprintln!("[Client] Compilation failed for {}: {}", returned_filename, error_msg);
-            return Err(io::Error::new(io::ErrorKind::Other, format!("Compilation failed: {}", error_msg)));
-        }
-    } else {
-        return Err(io::Error::new(io::ErrorKind::InvalidData, "Unexpected response from server"));
-    }
-    
-    Ok(())
+        (Status::Succeeded, Err(error_msg)) => {
+            eprintln!("[Client] {}", error_msg);
+            Err((Status::Failed, error_msg))
+        }
+        (Status::TimedOut, log) => {
+            let log = log.err().unwrap_or_default();
+            eprintln!("[Client] Compilation timed out for {}: {}", file_path, log);
+            Err((status, log))
+        }
+        (Status::Rejected, log) => {
+            let log = log.err().unwrap_or_default();
+            eprintln!("[Client] Server rejected {}: {}", file_path, log);
+            Err((status, log))
+        }
+        (Status::Corrupted, log) => {
+            let log = log.err().unwrap_or_default();
+            eprintln!("[Client] {} kept arriving corrupted at the server: {}", file_path, log);
+            Err((status, log))
+        }
+        (Status::Failed, log) => {
+            let log = log.err().unwrap_or_default();
+            eprintln!("[Client] Compilation failed for {}: {}", file_path, log);
+            Err((status, log))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::io::ErrorKind;
+    use std::net::{TcpListener, TcpStream};
+
+    use super::*;
+
+    // `count` small sources in a directory of their own.
+    fn sources(name: &str, count: usize) -> Vec<String> {
+        let dir = env::temp_dir().join(format!("dbs-client-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        (0..count)
+            .map(|i| {
+                let path = dir.join(format!("f{}.c", i));
+                fs::write(&path, format!("int f{}(void) {{ return {}; }}\n", i, i)).unwrap();
+                path.to_string_lossy().to_string()
+            })
+            .collect()
+    }
+
+    fn options(jobs: usize) -> SubmitOptions {
+        SubmitOptions {
+            compiler: None,
+            cc_args: Vec::new(),
+            mode: SourceMode::Raw,
+            priority: Priority::Normal,
+            max_in_flight: jobs,
+            local_fallback: false,
+            latency_budget: None,
+        }
+    }
+
+    // Answer the files in `ahead`, the last one sent first. Each object
+    // names the file it is for.
+    fn answer(stream: &mut TcpStream, ahead: &mut Vec<u32>) {
+        while let Some(index) = ahead.pop() {
+            let result = FileResult { status: Status::Succeeded, name: format!("f{}.o", index) };
+            BatchResult { index, result }.send(stream).unwrap();
+            send_bytes(stream, format!("object {}", index).as_bytes()).unwrap();
+        }
+    }
+
+    fn progress(count: usize, local_fallback: bool) -> Progress {
+        let files = (0..count).map(|i| format!("f{}.c", i)).collect();
+        Progress::new(Arc::new(files), local_fallback)
+    }
+
+    fn result(index: u32, status: Status, log: &str) -> Event {
+        let result = FileResult { status, name: format!("f{}.c", index) };
+        Event::Result(BatchResult { index, result }, Err(log.to_string()))
+    }
+
+    fn local_reason(progress: &Progress, index: usize) -> &str {
+        match &progress.state[index] {
+            FileState::Local(reason) => reason,
+            _ => panic!("f{}.c is not being compiled locally", index),
+        }
+    }
+
+    #[test]
+    fn unreachable_server_leaves_everything_to_the_local_compiler() {
+        let mut fallback = progress(2, true);
+        let actions = fallback.unreachable(io::Error::from(ErrorKind::ConnectionRefused));
+        assert_eq!(actions, [Action::CompileLocally(0), Action::CompileLocally(1)]);
+        assert!(local_reason(&fallback, 1).starts_with("server unreachable: "));
+        assert!(!fallback.finished());
+
+        fallback.handle(Event::Local(0, Ok(())), true);
+        fallback.handle(Event::Local(1, Err("error: x".to_string())), true);
+        assert!(matches!(fallback.state[0], FileState::Locally(true, _)));
+        assert!(matches!(fallback.state[1], FileState::Locally(false, _)));
+        assert!(fallback.finished());
+
+        let mut no_fallback = progress(2, false);
+        assert!(no_fallback.unreachable(io::Error::from(ErrorKind::ConnectionRefused)).is_empty());
+        assert!(no_fallback.state.iter().all(|s| matches!(s, FileState::OnServer(false))));
+        assert!(no_fallback.remote_error.is_some() && no_fallback.finished());
+    }
+
+    #[test]
+    fn rejected_and_timed_out_files_are_compiled_locally_but_failures_are_not() {
+        let mut batch = progress(3, true);
+        for index in 0..3 {
+            batch.handle(Event::Sent(index), true);
+        }
+        let rejected = batch.handle(result(0, Status::Rejected, "No connected worker offers compiler 'icc'\n"), true);
+        assert_eq!(rejected, [Action::CompileLocally(0)]);
+        assert_eq!(local_reason(&batch, 0), "rejected by the server: No connected worker offers compiler 'icc'");
+
+        assert_eq!(batch.handle(result(1, Status::TimedOut, ""), true), [Action::CompileLocally(1)]);
+        assert_eq!(local_reason(&batch, 1), "timed out on the server");
+
+        // The local compiler would fail the same way
+        assert!(batch.handle(result(2, Status::Failed, "error: x"), true).is_empty());
+        assert!(matches!(batch.state[2], FileState::OnServer(false)));
+
+        let mut no_fallback = progress(1, false);
+        no_fallback.handle(Event::Sent(0), true);
+        assert!(no_fallback.handle(result(0, Status::Rejected, "no"), true).is_empty());
+        assert!(matches!(no_fallback.state[0], FileState::OnServer(false)));
+    }
+
+    #[test]
+    fn file_over_the_latency_budget_goes_local_and_its_late_result_is_ignored() {
+        let mut batch = progress(2, true);
+        batch.handle(Event::Sent(0), true);
+        thread::sleep(Duration::from_millis(20));
+        batch.handle(Event::Sent(1), true);
+
+        assert_eq!(batch.over_budget(Duration::from_millis(10)), [Action::CompileLocally(0)]);
+        assert_eq!(local_reason(&batch, 0), "no result within 0.01 s");
+        assert!(batch.over_budget(Duration::from_secs(60)).is_empty());
+
+        // The server's answer comes in while the local compile runs
+        assert!(batch.handle(result(0, Status::Failed, "error: x"), true).is_empty());
+        assert_eq!(local_reason(&batch, 0), "no result within 0.01 s");
+        batch.handle(Event::Local(0, Ok(())), true);
+        assert!(matches!(batch.state[0], FileState::Locally(true, _)));
+
+        // Only with a fallback to go to
+        let mut no_fallback = progress(1, false);
+        no_fallback.handle(Event::Sent(0), true);
+        thread::sleep(Duration::from_millis(20));
+        assert!(no_fallback.over_budget(Duration::from_millis(10)).is_empty());
+    }
+
+    #[test]
+    fn dropped_connection_moves_only_unanswered_files() {
+        let mut batch = progress(3, true);
+        for index in 0..2 {
+            batch.handle(Event::Sent(index), true);
+        }
+        batch.handle(result(0, Status::Failed, "error: x"), true);
+
+        let reset = Event::RemoteDone(Err(io::Error::from(ErrorKind::ConnectionReset)));
+        assert_eq!(batch.handle(reset, true), [Action::CompileLocally(1), Action::CompileLocally(2)]);
+        assert!(local_reason(&batch, 2).starts_with("connection to the server failed: "));
+        assert!(matches!(batch.state[0], FileState::OnServer(false)));
+        assert!(batch.remote_error.is_some());
+
+        // Losing only the closing BatchEnd is no failure
+        let mut answered = progress(1, true);
+        answered.handle(Event::Sent(0), true);
+        answered.handle(result(0, Status::Failed, "error: x"), true);
+        let reset = Event::RemoteDone(Err(io::Error::from(ErrorKind::ConnectionReset)));
+        assert!(answered.handle(reset, true).is_empty());
+        assert!(answered.remote_error.is_none() && answered.finished());
+    }
+
+    #[test]
+    fn corrupted_sources_are_sent_again_then_compiled_locally() {
+        let mut batch = progress(1, true);
+        for _ in 1..SEND_ATTEMPTS {
+            batch.handle(Event::Sent(0), true);
+            assert_eq!(batch.handle(result(0, Status::Corrupted, "bad"), true), [Action::Resend(0)]);
+        }
+        batch.handle(Event::Sent(0), true);
+        assert_eq!(batch.handle(result(0, Status::Corrupted, "bad"), true), [Action::CompileLocally(0)]);
+        assert_eq!(local_reason(&batch, 0), "corrupted on the way to the server: bad");
+
+        // Nothing is sent once the batch is wrapping up
+        let mut closing = progress(1, true);
+        closing.handle(Event::Sent(0), true);
+        assert_eq!(closing.handle(result(0, Status::Corrupted, "bad"), false), [Action::CompileLocally(0)]);
+    }
+
+    #[test]
+    fn batch_keeps_to_its_window_and_matches_results_to_files() {
+        const JOBS: usize = 2;
+        let files = sources("window", 5);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+
+        // Answers only once the client stops sending, so a client that
+        // ignored its window would be caught with more files ahead
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let manifest = SubmitBatch::from_message(&Message::read(&mut stream).unwrap()).unwrap();
+            stream.set_read_timeout(Some(Duration::from_millis(200))).unwrap();
+            let (mut ahead, mut most_ahead) = (Vec::new(), 0);
+            loop {
+                match Message::read(&mut stream) {
+                    Ok(msg) if msg.op == OpCode::BatchFile => {
+                        ahead.push(BatchFile::from_message(&msg).unwrap().index);
+                        read_body(&mut stream).unwrap();
+                        most_ahead = most_ahead.max(ahead.len());
+                    }
+                    Ok(msg) => {
+                        assert_eq!(msg.op, OpCode::BatchEnd);
+                        break;
+                    }
+                    Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                        answer(&mut stream, &mut ahead)
+                    }
+                    Err(e) => panic!("{}", e),
+                }
+            }
+            answer(&mut stream, &mut ahead);
+            BatchEnd { error: None }.send(&mut stream).unwrap();
+            (manifest.names, most_ahead)
+        });
+
+        submit_files(files.clone(), &addr, options(JOBS)).unwrap();
+        let (names, most_ahead) = server.join().unwrap();
+        assert_eq!(names, ["f0.c", "f1.c", "f2.c", "f3.c", "f4.c"]);
+        assert_eq!(most_ahead, JOBS);
+        for (index, file) in files.iter().enumerate() {
+            assert_eq!(fs::read_to_string(object_path(file)).unwrap(), format!("object {}", index));
+        }
+        fs::remove_dir_all(Path::new(&files[0]).parent().unwrap()).ok();
+    }
 }
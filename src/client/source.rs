@@ -0,0 +1,110 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::process::Command;
+
+use clap::ValueEnum;
+
+use crate::utils::depfile::parse_make_rule;
+use crate::utils::flags::{normalize_path, strip_preprocessor_args};
+
+// How a source file's headers reach the worker.
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum SourceMode {
+    /// Send the .c file as-is; only system headers can be included
+    Raw,
+    /// Run the preprocessor locally and send the preprocessed translation unit
+    Preprocess,
+    /// Send the .c file together with every non-system header it includes
+    Bundle,
+}
+
+// What actually gets sent for one source file.
+pub struct PreparedSource {
+    pub filename: String,
+    pub contents: Vec<u8>,
+    pub args: Vec<String>,
+    // Absolute path of the source on this machine; empty unless bundled.
+    pub origin: String,
+    // (absolute path on this machine, contents)
+    pub headers: Vec<(String, Vec<u8>)>,
+}
+
+pub fn prepare_source(
+    file_path: &str,
+    mode: SourceMode,
+    compiler: &str,
+    args: &[String],
+) -> io::Result<PreparedSource> {
+    let path = Path::new(file_path);
+    let filename = path
+        .file_name()
+        .and_then(|n| n.to_str())
+        .unwrap_or("unknown.c")
+        .to_string();
+
+    match mode {
+        SourceMode::Raw => Ok(PreparedSource {
+            filename,
+            contents: fs::read(path)?,
+            args: args.to_vec(),
+            origin: String::new(),
+            headers: Vec::new(),
+        }),
+        SourceMode::Preprocess => {
+            // <cc> <args> -E file.c
+            let output = Command::new(compiler).args(args).arg("-E").arg(path).output()?;
+            if !output.status.success() {
+                return Err(io::Error::other(format!(
+                    "Preprocessing failed: {}",
+                    String::from_utf8_lossy(&output.stderr)
+                )));
+            }
+
+            // gcc and clang both treat .i as already-preprocessed C
+            let stem = path.file_stem().and_then(|n| n.to_str()).unwrap_or("unknown");
+            Ok(PreparedSource {
+                filename: format!("{}.i", stem),
+                contents: output.stdout,
+                args: strip_preprocessor_args(args),
+                origin: String::new(),
+                headers: Vec::new(),
+            })
+        }
+        SourceMode::Bundle => {
+            let mut headers = Vec::new();
+            for header in find_headers(path, compiler, args)? {
+                let contents = fs::read(&header)?;
+                headers.push((header, contents));
+            }
+
+            Ok(PreparedSource {
+                filename,
+                contents: fs::read(path)?,
+                args: args.to_vec(),
+                origin: normalize_path(path).to_string_lossy().to_string(),
+                headers,
+            })
+        }
+    }
+}
+
+// Ask the compiler for the non-system headers `path` includes (`-MM`) and
+// return them as absolute paths.
+fn find_headers(path: &Path, compiler: &str, args: &[String]) -> io::Result<Vec<String>> {
+    let output = Command::new(compiler).args(args).arg("-MM").arg(path).output()?;
+    if !output.status.success() {
+        return Err(io::Error::other(format!(
+            "Dependency scan failed: {}",
+            String::from_utf8_lossy(&output.stderr)
+        )));
+    }
+
+    let rule = String::from_utf8_lossy(&output.stdout);
+    let mut prerequisites = parse_make_rule(&rule).into_iter();
+    prerequisites.next(); // the source file itself
+
+    Ok(prerequisites
+        .map(|dep| normalize_path(Path::new(&dep)).to_string_lossy().to_string())
+        .collect())
+}
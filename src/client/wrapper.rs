@@ -0,0 +1,238 @@
+use std::env;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use clap::ValueEnum;
+
+use super::{SourceMode, compile_one};
+use crate::utils::flags::prepare_args;
+use crate::utils::net;
+
+// `dbs cc` only ever sees compiler arguments, so everything else comes from
+// the environment.
+const SERVER_VAR: &str = "DBS_SERVER";
+const COMPILER_VAR: &str = "DBS_CC";
+const MODE_VAR: &str = "DBS_MODE";
+const TLS_CA_VAR: &str = "DBS_TLS_CA";
+
+const DEFAULT_SERVER: &str = "127.0.0.1:9000";
+const DEFAULT_COMPILER: &str = "gcc";
+
+// Options whose value is the next argument, so it isn't mistaken for an input.
+const VALUE_OPTIONS: [&str; 9] = [
+    "-I", "-isystem", "-iquote", "-idirafter", "-include", "-imacros",
+    "-D", "-U", "-x",
+];
+
+// Dependency-file options with a value, which make and ninja add to every
+// compile along with -MD or -MMD.
+const DEP_VALUE_OPTIONS: [&str; 3] = ["-MF", "-MT", "-MQ"];
+
+// A compile that can go to the server.
+#[derive(Debug, PartialEq)]
+struct RemoteJob {
+    source: String,
+    output: PathBuf,
+    // Prepared like `flags::prepare_args`; without -c, -o or the source
+    args: Vec<String>,
+    // For the local preprocessing step, which writes the depfile: -MD or
+    // -MMD with -MF and the targets spelled out. Empty without -MD/-MMD.
+    deps: Vec<String>,
+}
+
+// Run one compiler invocation, on the server when it is a plain `-c` compile
+// of a single C file and with the real compiler otherwise. Returns the exit
+// code to leave with.
+pub fn run_cc(args: &[String]) -> i32 {
+    let compiler = env::var(COMPILER_VAR).unwrap_or_else(|_| DEFAULT_COMPILER.to_string());
+
+    let job = match plan_remote(args) {
+        Ok(job) => job,
+        // Linking, -E, dependency files and the like stay on this machine
+        Err(_) => return run_local(&compiler, args),
+    };
+
+    let server = env::var(SERVER_VAR).unwrap_or_else(|_| DEFAULT_SERVER.to_string());
+    let mode = match env::var(MODE_VAR) {
+        Ok(mode) => SourceMode::from_str(&mode, true).unwrap_or_else(|_| {
+            eprintln!("[Client] Unknown {} '{}', using preprocess", MODE_VAR, mode);
+            SourceMode::Preprocess
+        }),
+        // Preprocessing here means the worker needs none of our headers
+        Err(_) => SourceMode::Preprocess,
+    };
+    if let Some(ca) = env::var_os(TLS_CA_VAR) {
+        match net::client_tls(Path::new(&ca), None) {
+            Ok(config) => net::set_client_tls(config),
+            // Never send the source in the clear when TLS was asked for
+            Err(e) => {
+                eprintln!("[Client] Could not set up TLS ({}), compiling {} locally", e, job.source);
+                return run_local(&compiler, args);
+            }
+        }
+    }
+
+    let mut args_for_server = job.args;
+    if !job.deps.is_empty() {
+        // Only the preprocessing step can write the depfile here
+        if mode != SourceMode::Preprocess {
+            return run_local(&compiler, args);
+        }
+        // Stripped again before the preprocessed source is sent
+        args_for_server.extend(job.deps);
+    }
+
+    match compile_one(&job.source, &server, &compiler, &args_for_server, mode, &job.output) {
+        Ok(Ok(())) => 0,
+        // Compile again here so the diagnostics name the real paths
+        Ok(Err((status, _))) => {
+            eprintln!("[Client] {} did not compile on {} ({:?}), compiling locally", job.source, server, status);
+            run_local(&compiler, args)
+        }
+        Err(e) => {
+            eprintln!("[Client] Could not use {} ({}), compiling {} locally", server, e, job.source);
+            run_local(&compiler, args)
+        }
+    }
+}
+
+fn run_local(compiler: &str, args: &[String]) -> i32 {
+    match Command::new(compiler).args(args).status() {
+        Ok(status) => status.code().unwrap_or(1),
+        Err(e) => {
+            eprintln!("[Client] Failed to run {}: {}", compiler, e);
+            127
+        }
+    }
+}
+
+// Work out whether `args` is something the server can do for us. The error
+// says why not.
+fn plan_remote(args: &[String]) -> Result<RemoteJob, String> {
+    let mut compile_only = false;
+    let mut output = None;
+    let mut sources = Vec::new();
+    let mut rest = Vec::new();
+    let mut dep_kind = None;
+    let mut dep_file = None;
+    let mut dep_options = Vec::new();
+
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "-c" => compile_only = true,
+            "-o" => output = Some(iter.next().ok_or("'-o' is missing its path argument")?.clone()),
+            glued if glued.starts_with("-o") => output = Some(glued[2..].to_string()),
+            "-MD" | "-MMD" => dep_kind = Some(arg.clone()),
+            "-MP" => dep_options.push(arg.clone()),
+            option if DEP_VALUE_OPTIONS.contains(&option) => {
+                let value = iter.next().ok_or_else(|| format!("'{}' is missing its argument", arg))?;
+                match option {
+                    "-MF" => dep_file = Some(value.clone()),
+                    _ => dep_options.extend([arg.clone(), value.clone()]),
+                }
+            }
+            glued if DEP_VALUE_OPTIONS.iter().any(|opt| glued.starts_with(opt)) => match glued.split_at(3) {
+                ("-MF", path) => dep_file = Some(path.to_string()),
+                (option, value) => dep_options.extend([option.to_string(), value.to_string()]),
+            },
+            option if VALUE_OPTIONS.contains(&option) => {
+                rest.push(arg.clone());
+                rest.extend(iter.next().cloned());
+            }
+            option if option.starts_with('-') => rest.push(arg.clone()),
+            source if source.ends_with(".c") => sources.push(arg.clone()),
+            _ => return Err(format!("'{}' is not a C source", arg)),
+        }
+    }
+
+    if !compile_only {
+        return Err("not a compile-only (-c) invocation".to_string());
+    }
+    let [source] = sources.as_slice() else {
+        return Err(format!("{} C sources instead of one", sources.len()));
+    };
+    let args = prepare_args(&rest)?;
+
+    // Without -o, gcc writes the object to the current directory
+    let output = match output {
+        Some(output) => PathBuf::from(output),
+        None => PathBuf::from(Path::new(source).file_name().unwrap_or_default()).with_extension("o"),
+    };
+    let deps = match dep_kind {
+        Some(kind) => dep_args(kind, dep_file, dep_options, &output),
+        None if dep_file.is_some() || !dep_options.is_empty() => {
+            return Err("dependency options without -MD or -MMD".to_string());
+        }
+        None => Vec::new(),
+    };
+    Ok(RemoteJob {
+        source: source.clone(),
+        output,
+        args,
+        deps,
+    })
+}
+
+// Spell out what -MD or -MMD would do in a compile, for a preprocessing
+// step: without -o naming the object, it needs the depfile and the target.
+fn dep_args(kind: String, file: Option<String>, mut options: Vec<String>, output: &Path) -> Vec<String> {
+    let file = file.unwrap_or_else(|| output.with_extension("d").to_string_lossy().to_string());
+    if !options.iter().any(|opt| opt == "-MT" || opt == "-MQ") {
+        options.extend(["-MT".to_string(), output.to_string_lossy().to_string()]);
+    }
+    [kind, "-MF".to_string(), file].into_iter().chain(options).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(list: &[&str]) -> Vec<String> {
+        list.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn plain_compile_goes_remote() {
+        let job = plan_remote(&args(&["-O2", "-D", "X=1", "-c", "src/a.c", "-o", "build/a.o"])).unwrap();
+        assert_eq!(job.source, "src/a.c");
+        assert_eq!(job.output, PathBuf::from("build/a.o"));
+        assert_eq!(job.args, args(&["-O2", "-D", "X=1"]));
+
+        let job = plan_remote(&args(&["-c", "dir/b.c"])).unwrap();
+        assert_eq!(job.output, PathBuf::from("b.o"));
+        assert!(job.deps.is_empty());
+    }
+
+    #[test]
+    fn dependency_files_are_left_to_the_preprocessor() {
+        // What CMake's Makefile and Ninja generators pass
+        let job = plan_remote(&args(&["-O2", "-MD", "-MT", "obj/a.o", "-MF", "obj/a.o.d", "-o", "obj/a.o", "-c", "a.c"]))
+            .unwrap();
+        assert_eq!(job.args, args(&["-O2"]));
+        assert_eq!(job.deps, args(&["-MD", "-MF", "obj/a.o.d", "-MT", "obj/a.o"]));
+
+        // automake's style, with the depfile and target implied
+        let job = plan_remote(&args(&["-MMD", "-MP", "-c", "-o", "out/b.o", "b.c"])).unwrap();
+        assert_eq!(job.deps, args(&["-MMD", "-MF", "out/b.d", "-MP", "-MT", "out/b.o"]));
+
+        let job = plan_remote(&args(&["-MD", "-MFc.dep", "-MQ$(OBJ)", "-c", "c.c"])).unwrap();
+        assert_eq!(job.deps, args(&["-MD", "-MF", "c.dep", "-MQ", "$(OBJ)"]));
+    }
+
+    #[test]
+    fn everything_else_stays_local() {
+        for invocation in [
+            &["a.o", "b.o", "-o", "app"][..],
+            &["a.c", "-o", "app"],
+            &["-E", "a.c"],
+            &["-c", "a.c", "b.c"],
+            &["-c", "a.s"],
+            &["-M", "a.c"],
+            &["-c", "a.c", "-MF", "a.d"],
+            &["-c", "a.c", "-fplugin=x.so"],
+        ] {
+            assert!(plan_remote(&args(invocation)).is_err(), "remote: {:?}", invocation);
+        }
+    }
+}
@@ -4,41 +4,147 @@ mod server;
 mod utils;
 mod worker;
 
+use std::path::Path;
+use std::time::Duration;
+
 use clap::Parser;
 use cli::{Cli, Commands};
-use client::submit_files;
+use client::{SubmitOptions, run_cc, submit_files};
 use server::server_node;
-use utils::config;
+use utils::{config, net};
+use worker::link::{LinkKind, LinkTarget};
 use worker::{controller::controller_node, worker_node};
 
 fn main() {
+    // Installed as a `dbs-cc` symlink, every argument is for the compiler
+    let mut argv = std::env::args();
+    if argv
+        .next()
+        .is_some_and(|argv0| Path::new(&argv0).file_stem().is_some_and(|stem| stem == "dbs-cc"))
+    {
+        std::process::exit(run_cc(&argv.collect::<Vec<_>>()));
+    }
+    
     let cli = Cli::parse();
 
     match cli.command {
         Commands::Build {
             files,
+            compdb,
             workers,
             address,
+            cc,
+            exec_timeout,
+            max_retries,
+            output,
+            static_lib,
+            shared_lib,
+            link_args,
+            incremental,
+            cc_args,
         } => {
             config::set_worker_count(workers);
             config::set_server_addr(address);
+            config::set_default_compiler(cc);
+            config::set_exec_timeout(Duration::from_secs(exec_timeout));
+            config::set_max_retries(max_retries);
+            
+            let link_target = [
+                (LinkKind::Executable, output),
+                (LinkKind::StaticLib, static_lib),
+                (LinkKind::SharedLib, shared_lib),
+            ]
+            .into_iter()
+            .find_map(|(kind, path)| path.map(|output| LinkTarget { kind, output, args: link_args.clone() }));
             
-            controller_node(files);
+            if !controller_node(files, compdb, &cc_args, link_target, incremental) {
+                std::process::exit(1);
+            }
         }
-        Commands::Serve { workers, address } => {
+        Commands::Serve {
+            workers,
+            address,
+            cc,
+            cache_dir,
+            cache_size,
+            no_cache,
+            queue_timeout,
+            exec_timeout,
+            max_retries,
+            tls_cert,
+            tls_key,
+            tls_worker_ca,
+        } => {
+            if let (Some(cert), Some(key)) = (tls_cert, tls_key) {
+                net::set_server_tls(or_exit(net::server_tls(&cert, &key, tls_worker_ca.as_deref())));
+            }
             config::set_worker_count(workers);
             config::set_server_addr(address);
+            config::set_default_compiler(cc);
+            config::set_queue_timeout(Duration::from_secs(queue_timeout));
+            config::set_exec_timeout(Duration::from_secs(exec_timeout));
+            config::set_max_retries(max_retries);
             
-            server_node();
+            let cache_dir = if no_cache { None } else { Some(cache_dir) };
+            server_node(cache_dir, cache_size * 1024 * 1024);
         }
-        Commands::Submit { files, server } => {
-            if let Err(e) = submit_files(files, &server) {
+        Commands::Submit {
+            files,
+            server,
+            cc,
+            mode,
+            priority,
+            jobs,
+            local_fallback,
+            latency_budget,
+            tls_ca,
+            cc_args,
+        } => {
+            if let Some(ca) = tls_ca {
+                net::set_client_tls(or_exit(net::client_tls(&ca, None)));
+            }
+            let options = SubmitOptions {
+                compiler: cc,
+                cc_args,
+                mode,
+                priority,
+                max_in_flight: jobs,
+                local_fallback,
+                latency_budget: latency_budget.map(Duration::from_secs),
+            };
+            if let Err(e) = submit_files(files, &server, options) {
                 eprintln!("Error: {}", e);
                 std::process::exit(1);
             }
         }
-        Commands::Worker { id } => {
-            worker_node(&id);
+        Commands::Cc { args } => {
+            std::process::exit(run_cc(&args));
+        }
+        Commands::Worker {
+            id,
+            server,
+            compilers,
+            no_compression,
+            local_build,
+            tls_ca,
+            tls_cert,
+            tls_key,
+        } => {
+            if let Some(ca) = tls_ca {
+                let identity = tls_cert.as_deref().zip(tls_key.as_deref());
+                net::set_client_tls(or_exit(net::client_tls(&ca, identity)));
+            }
+            config::set_server_addr(server);
+
+            let id = id.unwrap_or_else(|| std::process::id().to_string());
+            worker_node(&id, &compilers, !no_compression, local_build);
         }
     }
 }
+
+fn or_exit<T>(result: std::io::Result<T>) -> T {
+    result.unwrap_or_else(|e| {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
+    })
+}
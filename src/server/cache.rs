@@ -0,0 +1,333 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufRead};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::SystemTime;
+
+use sha2::{Digest, Sha256};
+
+use super::task::Task;
+use crate::utils::flags::{map_path_args, relative_path};
+
+static CACHE: OnceLock<CompileCache> = OnceLock::new();
+
+// The server's cache, if one was opened. The build controller runs without.
+pub fn global() -> Option<&'static CompileCache> {
+    CACHE.get()
+}
+
+pub fn init(dir: PathBuf, max_bytes: u64) -> io::Result<()> {
+    let cache = CompileCache::open(dir, max_bytes)?;
+    CACHE.set(cache).ok();
+    Ok(())
+}
+
+// Object files on disk, named by the hash of everything that went into them.
+// Least recently used entries are evicted once the total size exceeds
+// `max_bytes`; file modification times record use so the order survives a
+// restart.
+pub struct CompileCache {
+    dir: PathBuf,
+    max_bytes: u64,
+    state: Mutex<CacheState>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    // Makes temporary file names unique when two stores of a key overlap.
+    temp_seq: AtomicU64,
+}
+
+struct CacheState {
+    entries: HashMap<String, Entry>,
+    total_bytes: u64,
+}
+
+struct Entry {
+    size: u64,
+    last_used: SystemTime,
+}
+
+impl CompileCache {
+    fn open(dir: PathBuf, max_bytes: u64) -> io::Result<Self> {
+        fs::create_dir_all(&dir)?;
+
+        let mut entries = HashMap::new();
+        let mut total_bytes = 0;
+        for dir_entry in fs::read_dir(&dir)? {
+            let dir_entry = dir_entry?;
+            let path = dir_entry.path();
+            let Some(key) = entry_key(&path) else {
+                // Leftover from an interrupted store
+                fs::remove_file(&path).ok();
+                continue;
+            };
+            let meta = dir_entry.metadata()?;
+            total_bytes += meta.len();
+            entries.insert(
+                key,
+                Entry {
+                    size: meta.len(),
+                    last_used: meta.modified().unwrap_or(SystemTime::UNIX_EPOCH),
+                },
+            );
+        }
+
+        println!(
+            "[Cache] Loaded {} entries ({} KiB) from {}",
+            entries.len(),
+            total_bytes / 1024,
+            dir.display()
+        );
+
+        let cache = Self {
+            dir,
+            max_bytes,
+            state: Mutex::new(CacheState { entries, total_bytes }),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            temp_seq: AtomicU64::new(0),
+        };
+        cache.evict(&mut cache.state.lock().unwrap());
+        Ok(cache)
+    }
+
+    // Look up the first of `keys` that is cached and count a hit or a miss.
+    // Returns the path of the cached object, which eviction may remove at
+    // any time after this returns.
+    pub fn get(&self, keys: &[String]) -> Option<PathBuf> {
+        let mut state = self.state.lock().unwrap();
+        for key in keys {
+            let Some(entry) = state.entries.get_mut(key) else {
+                continue;
+            };
+            let path = self.entry_path(key);
+            match File::options().write(true).open(&path) {
+                Ok(file) => {
+                    entry.last_used = SystemTime::now();
+                    file.set_modified(entry.last_used).ok();
+                    self.hits.fetch_add(1, Ordering::Relaxed);
+                    return Some(path);
+                }
+                Err(_) => {
+                    // Deleted behind our back
+                    let size = entry.size;
+                    state.entries.remove(key);
+                    state.total_bytes -= size;
+                }
+            }
+        }
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        None
+    }
+
+    // Store a copy of the object file at `object`.
+    pub fn store(&self, key: &str, object: &Path) {
+        let path = self.entry_path(key);
+        let seq = self.temp_seq.fetch_add(1, Ordering::Relaxed);
+        let temp_path = self.dir.join(format!("{}.{}.tmp", key, seq));
+        let copied = fs::copy(object, &temp_path).and_then(|size| fs::rename(&temp_path, &path).map(|_| size));
+        let size = match copied {
+            Ok(size) => size,
+            Err(e) => {
+                eprintln!("[Cache] Failed to store {}: {}", key, e);
+                fs::remove_file(&temp_path).ok();
+                return;
+            }
+        };
+
+        let mut state = self.state.lock().unwrap();
+        let entry = Entry {
+            size,
+            last_used: SystemTime::now(),
+        };
+        if let Some(old) = state.entries.insert(key.to_string(), entry) {
+            state.total_bytes -= old.size;
+        }
+        state.total_bytes += size;
+        self.evict(&mut state);
+    }
+
+    // (hits, misses)
+    pub fn stats(&self) -> (u64, u64) {
+        (self.hits.load(Ordering::Relaxed), self.misses.load(Ordering::Relaxed))
+    }
+
+    fn evict(&self, state: &mut CacheState) {
+        while state.total_bytes > self.max_bytes {
+            let Some(oldest) = state
+                .entries
+                .iter()
+                .min_by_key(|(_, e)| e.last_used)
+                .map(|(k, _)| k.clone())
+            else {
+                break;
+            };
+            let entry = state.entries.remove(&oldest).unwrap();
+            state.total_bytes -= entry.size;
+            fs::remove_file(self.entry_path(&oldest)).ok();
+        }
+    }
+
+    fn entry_path(&self, key: &str) -> PathBuf {
+        self.dir.join(format!("{}.o", key))
+    }
+}
+
+// Hash of everything about a task that determines its object file, except
+// which compiler version ends up running it.
+//
+// Only self-contained submissions have one: preprocessed sources, and bundles
+// that carry their headers. A raw source may include headers from the
+// worker's disk or from `-I` directories the server never sees, so editing
+// one of those would not change the key.
+//
+// Paths are hashed relative to the source's directory, and preprocessor line
+// markers are left out, so the same code checked out in different places
+// shares entries. With debug info the paths end up in the object, so then
+// they are hashed as they are.
+pub fn task_digest(task: &Task, source: &mut impl BufRead) -> Option<Vec<u8>> {
+    let filename = Path::new(&task.path)
+        .file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or_default();
+
+    let preprocessed = Path::new(&filename).extension().is_some_and(|ext| ext == "i");
+    let bundled = !task.origin.is_empty();
+    if !preprocessed && !bundled {
+        return None;
+    }
+
+    let debug_info = task.args.iter().any(|arg| arg.starts_with("-g"));
+    let base = Path::new(&task.origin).parent().unwrap_or(Path::new("/"));
+    let portable = |path: &str| -> String {
+        if debug_info || !bundled || !Path::new(path).is_absolute() {
+            return path.to_string();
+        }
+        relative_path(base, Path::new(path)).to_string_lossy().to_string()
+    };
+
+    let mut hasher = Sha256::new();
+    hash_field(&mut hasher, filename.as_bytes());
+    hash_field(&mut hasher, portable(&task.origin).as_bytes());
+    let args = map_path_args(&task.args, portable);
+    hash_field(&mut hasher, &(args.len() as u64).to_be_bytes());
+    for arg in &args {
+        hash_field(&mut hasher, arg.as_bytes());
+    }
+    let mut headers: Vec<(String, &[u8])> = task
+        .headers
+        .iter()
+        .map(|(header_path, contents)| (portable(header_path), contents.as_slice()))
+        .collect();
+    headers.sort_by(|a, b| a.0.cmp(&b.0));
+    hash_field(&mut hasher, &(headers.len() as u64).to_be_bytes());
+    for (header_path, contents) in headers {
+        hash_field(&mut hasher, header_path.as_bytes());
+        hash_field(&mut hasher, contents);
+    }
+    let source_digest = if preprocessed && !debug_info {
+        hash_without_line_markers(source)
+    } else {
+        let mut source_hasher = Sha256::new();
+        io::copy(source, &mut source_hasher).map(|_| source_hasher.finalize().to_vec())
+    };
+    hash_field(&mut hasher, &source_digest.ok()?);
+    Some(hasher.finalize().to_vec())
+}
+
+// Hash of the source without the `# 12 "/path/to/file.h"` lines the
+// preprocessor emits; they only record where code came from.
+fn hash_without_line_markers(source: &mut impl BufRead) -> io::Result<Vec<u8>> {
+    let mut hasher = Sha256::new();
+    let mut line = Vec::new();
+    while source.read_until(b'\n', &mut line)? > 0 {
+        let is_marker = line.starts_with(b"# ") && line.get(2).is_some_and(u8::is_ascii_digit);
+        if !is_marker {
+            hasher.update(&line);
+        }
+        line.clear();
+    }
+    Ok(hasher.finalize().to_vec())
+}
+
+// Cache key for a task digest compiled by a specific compiler build.
+pub fn cache_key(digest: &[u8], compiler: &str, version: &str) -> String {
+    let mut hasher = Sha256::new();
+    hash_field(&mut hasher, digest);
+    hash_field(&mut hasher, compiler.as_bytes());
+    hash_field(&mut hasher, version.as_bytes());
+    hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+// Length-prefix every field so adjacent fields can't run into each other.
+fn hash_field(hasher: &mut Sha256, bytes: &[u8]) {
+    hasher.update((bytes.len() as u64).to_be_bytes());
+    hasher.update(bytes);
+}
+
+fn entry_key(path: &Path) -> Option<String> {
+    if path.extension()? != "o" {
+        return None;
+    }
+    let key = path.file_stem()?.to_str()?;
+    (key.len() == 64 && key.chars().all(|c| c.is_ascii_hexdigit())).then(|| key.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bundled(root: &str, args: &[&str], header: &[u8]) -> Task {
+        Task::new(
+            1,
+            "temp_builds/job-1/a.c".to_string(),
+            "gcc".to_string(),
+            args.iter().map(|a| a.replace("{root}", root)).collect(),
+        )
+        .with_headers(
+            format!("{}/src/a.c", root),
+            vec![(format!("{}/include/a.h", root), header.to_vec())],
+        )
+    }
+
+    #[test]
+    fn raw_sources_are_not_cacheable() {
+        let task = Task::new(1, "job/a.c".to_string(), "gcc".to_string(), Vec::new());
+        assert!(task_digest(&task, &mut &b"int x;"[..]).is_none());
+    }
+
+    #[test]
+    fn bundles_in_different_checkouts_share_a_digest() {
+        let a = bundled("/home/alice/proj", &["-I", "{root}/include", "-O2"], b"#define N 1");
+        let b = bundled("/home/bob/work/proj", &["-I", "{root}/include", "-O2"], b"#define N 1");
+        assert_eq!(task_digest(&a, &mut &b"int x;"[..]), task_digest(&b, &mut &b"int x;"[..]));
+    }
+
+    #[test]
+    fn header_edits_change_the_digest() {
+        let a = bundled("/p", &[], b"#define N 1");
+        let b = bundled("/p", &[], b"#define N 2");
+        assert_ne!(task_digest(&a, &mut &b"int x;"[..]), task_digest(&b, &mut &b"int x;"[..]));
+    }
+
+    #[test]
+    fn debug_builds_keep_absolute_paths() {
+        let a = bundled("/home/alice/proj", &["-g"], b"");
+        let b = bundled("/home/bob/proj", &["-g"], b"");
+        assert_ne!(task_digest(&a, &mut &b"int x;"[..]), task_digest(&b, &mut &b"int x;"[..]));
+    }
+
+    #[test]
+    fn preprocessed_sources_ignore_line_markers() {
+        let task = Task::new(1, "job/a.i".to_string(), "gcc".to_string(), Vec::new());
+        let alice = b"# 1 \"/home/alice/a.c\"\nint x;\n# 3 \"/home/alice/a.c\"\n";
+        let bob = b"# 1 \"/home/bob/a.c\"\nint x;\n# 3 \"/home/bob/a.c\"\n";
+        assert_eq!(task_digest(&task, &mut &alice[..]), task_digest(&task, &mut &bob[..]));
+        assert_ne!(task_digest(&task, &mut &alice[..]), task_digest(&task, &mut &b"int y;\n"[..]));
+    }
+}
@@ -1,135 +1,498 @@
-use std::fs;
-use std::io::{self, Write};
-use std::net::TcpStream;
-use std::path::PathBuf;
+use std::fs::{self, File};
+use std::io::{self, BufReader};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::Receiver;
 use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
 
-use crate::utils::protocol::{Message, OpCode};
+use super::cache::{self, cache_key, task_digest};
+use super::jobs::{JobEvent, JobId, JobTable};
+use super::queue::TaskQueue;
+use super::session::WorkerRegistry;
+use super::task::Task;
+use super::transfers;
+use super::workspace::{JobWorkspace, sanitize_filename};
+use crate::utils::config;
+use crate::utils::net::Connection;
+use crate::utils::protocol::{
+    BatchEnd, BatchFile, BatchResult, FileResult, Message, OpCode, Payload, Priority, Resume, Status,
+    SubmitBatch, SubmitFile, Submission, is_integrity_error, read_body_to, send_body, send_bytes,
+};
 
-// Handle a client connection that submits files for compilation
+// Slack on top of the execution timeout for the worker to report back.
+const EXEC_GRACE: Duration = Duration::from_secs(5);
+
+// Handle a client connection whose SubmitFile message has already been read
 pub fn handle_client_session(
-    mut stream: TcpStream,
-    queue: Arc<Mutex<Vec<String>>>,
-    results: Arc<Mutex<Vec<(String, bool, String)>>>,
+    mut stream: Connection,
+    msg: Message,
+    queue: Arc<Mutex<TaskQueue>>,
+    jobs: Arc<JobTable>,
+    workers: WorkerRegistry,
 ) -> io::Result<()> {
-    // Receive SubmitFile message
-    let msg = Message::read(&mut stream)?;
+    // A client of another version or a garbled payload is told why, rather
+    // than guessed at
+    let submit = SubmitFile::from_message(&msg).and_then(|submit| Ok((submit, receive_source(&mut stream, &jobs)?)));
+    let (submit, upload) = match submit {
+        Ok((submit, Ok(upload))) => (submit, upload),
+        Ok((submit, Err(e))) => {
+            let (result, body) = refused_source(&submit.submission.filename, e);
+            result.send(&mut stream)?;
+            return send_reply_body(&mut stream, body);
+        }
+        Err(e) => {
+            eprintln!("[Server] Refused submission: {}", e);
+            let (result, body) = file_result(Status::Rejected, "", e.to_string().as_bytes());
+            result.send(&mut stream)?;
+            return send_reply_body(&mut stream, body);
+        }
+    };
     
-    if msg.op != OpCode::SubmitFile {
-        return Err(io::Error::new(
-            io::ErrorKind::InvalidData,
-            "Expected SubmitFile message",
-        ));
+    let owner = client_name(&stream);
+    let (result, body) =
+        process_submission(submit.submission, upload, &owner, submit.priority, &queue, &jobs, &workers);
+    result.send(&mut stream)?;
+    send_reply_body(&mut stream, body)
+}
+
+// A job opened for a submission, with its source received into the job's
+// workspace. The source is renamed to the submitted name once that has been
+// checked.
+struct Upload {
+    job_id: JobId,
+    done: Receiver<JobEvent>,
+    workspace: JobWorkspace,
+    source: PathBuf,
+}
+
+// Name the source is received under.
+const UPLOAD_NAME: &str = "upload";
+
+// Open a job and stream the source that follows into its workspace. The
+// outer error means the connection failed; the inner one that the source
+// couldn't be stored or failed its integrity check, with the connection
+// still in step.
+fn receive_source(stream: &mut Connection, jobs: &JobTable) -> io::Result<io::Result<Upload>> {
+    let (job_id, done) = jobs.register();
+    let workspace = JobWorkspace::create(job_id);
+    // Without a workspace the source is still read off the connection
+    let source = workspace.as_ref().map_or_else(|_| PathBuf::new(), |w| w.file(UPLOAD_NAME));
+    let received = read_body_to(stream, &source).inspect_err(|_| jobs.cancel(job_id))?;
+    let upload = workspace.and_then(|workspace| {
+        received?;
+        Ok(Upload { job_id, done, workspace, source })
+    });
+    if upload.is_err() {
+        jobs.cancel(job_id);
+    }
+    Ok(upload)
+}
+
+// The answer to a source that didn't arrive intact.
+fn refused_source(filename: &str, e: io::Error) -> (FileResult, ReplyBody) {
+    eprintln!("[Server] Source of {}: {}", filename, e);
+    // The client sends a corrupted source again
+    if is_integrity_error(&e) {
+        return file_result(Status::Corrupted, filename, e.to_string().as_bytes());
     }
+    let error_msg = format!("Failed to stage {}: {}", filename, e);
+    file_result(Status::Rejected, filename, error_msg.as_bytes())
+}
+
+// Handle a connection that opened with Resume: send the rest of an object
+// whose transfer broke off.
+pub fn handle_resume(mut stream: Connection, msg: Message) -> io::Result<()> {
+    let opened = Resume::from_message(&msg).and_then(|resume| {
+        let (file, size) = transfers::open(&resume.transfer)?;
+        if resume.offset > size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("offset {} is past the end of transfer '{}'", resume.offset, resume.transfer),
+            ));
+        }
+        Ok((resume, file, size))
+    });
     
-    // Parse payload: [filename_len (4 bytes)][filename][file_contents]
-    if msg.payload.len() < 4 {
-        return Err(io::Error::new(
-            io::ErrorKind::InvalidData,
-            "Invalid payload format",
-        ));
+    match opened {
+        Ok((resume, mut file, size)) => {
+            println!(
+                "[Server] Resuming transfer {} at {} of {} bytes",
+                resume.transfer, resume.offset, size
+            );
+            FileResult { status: Status::Succeeded, name: String::new() }.send(&mut stream)?;
+            send_body(&mut stream, &mut file, size, resume.offset, &resume.transfer)
+        }
+        Err(e) => {
+            eprintln!("[Server] Refused resume: {}", e);
+            let (result, body) = file_result(Status::Rejected, "", e.to_string().as_bytes());
+            result.send(&mut stream)?;
+            send_reply_body(&mut stream, body)
+        }
     }
+}
+
+// Handle a client connection that opened with a SubmitBatch manifest. The
+// client then sends BatchFile messages and finally BatchEnd; each file is
+// queued as soon as it arrives and its BatchResult is sent the moment it is
+// done, so results stream back in completion order. Once every result is
+// out, BatchEnd is echoed back.
+pub fn handle_batch_session(
+    mut stream: Connection,
+    manifest: Message,
+    queue: Arc<Mutex<TaskQueue>>,
+    jobs: Arc<JobTable>,
+    workers: WorkerRegistry,
+) -> io::Result<()> {
+    let batch = match SubmitBatch::from_message(&manifest) {
+        Ok(batch) => batch,
+        Err(e) => {
+            eprintln!("[Server] Refused batch: {}", e);
+            return BatchEnd { error: Some(e.to_string()) }.send(&mut stream);
+        }
+    };
+    let file_count = batch.names.len() as u32;
+    
+    let owner = client_name(&stream);
+    println!("[Server] Batch of {} files from {}", file_count, owner);
     
-    let filename_len = u32::from_be_bytes(msg.payload[0..4].try_into().unwrap()) as usize;
-    if msg.payload.len() < 4 + filename_len {
-        return Err(io::Error::new(
-            io::ErrorKind::InvalidData,
-            "Invalid filename length",
-        ));
+    let writer = Arc::new(Mutex::new(stream.try_clone()?));
+    let mut pending = Vec::new();
+    loop {
+        let msg = Message::read(&mut stream)?;
+        match msg.op {
+            OpCode::BatchFile => {
+                let file = BatchFile::from_message(&msg)?;
+                if file.index >= file_count {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid batch file index"));
+                }
+                let upload = match receive_source(&mut stream, &jobs)? {
+                    Ok(upload) => upload,
+                    // Answered at once so the client can send it again
+                    Err(e) => {
+                        let (result, body) = refused_source(&file.submission.filename, e);
+                        let mut writer = writer.lock().unwrap();
+                        BatchResult { index: file.index, result }.send(&mut *writer)?;
+                        send_reply_body(&mut writer, body)?;
+                        continue;
+                    }
+                };
+                
+                let (queue, jobs, workers) = (Arc::clone(&queue), Arc::clone(&jobs), Arc::clone(&workers));
+                let (owner, writer) = (owner.clone(), Arc::clone(&writer));
+                pending.push(thread::spawn(move || {
+                    let (result, body) = process_submission(
+                        file.submission,
+                        upload,
+                        &owner,
+                        batch.priority,
+                        &queue,
+                        &jobs,
+                        &workers,
+                    );
+                    // The body must follow its own result, not another one
+                    let mut writer = writer.lock().unwrap();
+                    BatchResult { index: file.index, result }
+                        .send(&mut *writer)
+                        .and_then(|_| send_reply_body(&mut writer, body))
+                        .ok();
+                }));
+            }
+            OpCode::BatchEnd => break,
+            op => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Unexpected {:?} in a batch", op),
+                ));
+            }
+        }
     }
     
-    let filename = String::from_utf8_lossy(&msg.payload[4..4 + filename_len]).to_string();
-    let file_contents = &msg.payload[4 + filename_len..];
+    for handle in pending {
+        handle.join().ok();
+    }
+    BatchEnd { error: None }.send(&mut *writer.lock().unwrap())
+}
+
+// Clients take turns in the queue by address.
+fn client_name(stream: &Connection) -> String {
+    stream.peer_addr().map(|a| a.ip().to_string()).unwrap_or_default()
+}
+
+// Compile one submitted file and return the result for it, waiting as long
+// as that takes. Never fails: problems are reported to the client in the
+// result. Its job is closed either way.
+fn process_submission(
+    submission: Submission,
+    upload: Upload,
+    owner: &str,
+    priority: Priority,
+    queue: &Mutex<TaskQueue>,
+    jobs: &JobTable,
+    workers: &WorkerRegistry,
+) -> (FileResult, ReplyBody) {
+    let job_id = upload.job_id;
+    let reply = compile_submission(submission, upload, owner, priority, queue, workers);
+    jobs.cancel(job_id);
+    reply
+}
+
+fn compile_submission(
+    submission: Submission,
+    upload: Upload,
+    owner: &str,
+    priority: Priority,
+    queue: &Mutex<TaskQueue>,
+    workers: &WorkerRegistry,
+) -> (FileResult, ReplyBody) {
+    let Upload { job_id, done, workspace, source } = upload;
+    let Submission { filename, mut compiler, args, origin, headers } = submission;
+    
+    // An empty compiler means "whatever this server was started with"
+    if compiler.is_empty() {
+        compiler = config::get_default_compiler().to_string();
+    }
     
-    println!("[Server] Client submitted: {}", filename);
+    println!("[Server] Client submitted: {} ({}, {} headers)", filename, compiler, headers.len());
     
-    // Save file temporarily
-    let temp_dir = PathBuf::from("temp_builds");
-    fs::create_dir_all(&temp_dir)?;
+    // The name becomes a path on our disk, so it must not point anywhere else
+    if let Err(error_msg) = sanitize_filename(&filename) {
+        eprintln!("[Server] {}", error_msg);
+        return file_result(Status::Rejected, &filename, error_msg.as_bytes());
+    }
+    
+    // Fail fast rather than letting the task sit in the queue forever
+    if !workers.lock().unwrap().values().any(|w| w.supports(&compiler)) {
+        let error_msg = format!("No connected worker offers compiler '{}'", compiler);
+        eprintln!("[Server] {}", error_msg);
+        return file_result(Status::Rejected, &filename, error_msg.as_bytes());
+    }
     
-    let temp_file_path = temp_dir.join(&filename);
-    fs::write(&temp_file_path, file_contents)?;
+    let output_filename = Path::new(&filename).with_extension("o").to_string_lossy().to_string();
+    // Not a job yet: ID and path are filled in once it is staged for a worker
+    let mut task = Task::new(0, filename.clone(), compiler, args)
+        .with_headers(origin, headers)
+        .with_owner(owner.to_string(), priority);
+    
+    // Answer straight from the cache if any live compiler build has seen this input
+    if let Some(cache) = cache::global()
+        && let Some(digest) = File::open(&source).ok().and_then(|file| task_digest(&task, &mut BufReader::new(file)))
+    {
+        let mut versions: Vec<String> = workers
+            .lock()
+            .unwrap()
+            .values()
+            .filter_map(|w| w.compiler_version(&task.compiler).map(str::to_string))
+            .collect();
+        versions.sort();
+        versions.dedup();
+        
+        let keys: Vec<String> = versions
+            .iter()
+            .map(|v| cache_key(&digest, &task.compiler, v))
+            .collect();
+        
+        let hit = cache.get(&keys);
+        let (hits, misses) = cache.stats();
+        if let Some(cached) = hit {
+            println!("[Cache] Hit for {} ({} hits, {} misses)", filename, hits, misses);
+            match transfers::keep(&cached, true) {
+                Ok((file, size, transfer)) => {
+                    let result = FileResult { status: Status::Succeeded, name: output_filename };
+                    return (result, ReplyBody::Object { file, size, transfer });
+                }
+                // Evicted in the meantime; compile it after all
+                Err(e) => eprintln!("[Cache] Failed to send cached object for {}: {}", filename, e),
+            }
+        } else {
+            println!("[Cache] Miss for {} ({} hits, {} misses)", filename, hits, misses);
+        }
+    }
     
-    let temp_file_str = temp_file_path.to_string_lossy().to_string();
+    // Each job compiles in its own directory, so clients submitting files
+    // with the same name never see each other's sources or objects
+    let temp_file_path = workspace.file(&filename);
+    if let Err(e) = fs::rename(&source, &temp_file_path) {
+        let error_msg = format!("Failed to stage {}: {}", filename, e);
+        eprintln!("[Server] {}", error_msg);
+        return file_result(Status::Rejected, &filename, error_msg.as_bytes());
+    }
+    task.id = job_id;
+    task.path = temp_file_path.to_string_lossy().to_string();
     
     // Add to build queue
     {
         let mut q = queue.lock().unwrap();
-        q.push(temp_file_str.clone());
+        q.push(task);
         println!("[Server] Added {} to queue. Queue size: {}", filename, q.len());
     }
     
     println!("[Server] Waiting for worker to compile {}...", filename);
     
-    // Wait for compilation to complete
-    // Poll results until our file appears
-    let output_file = temp_file_path.to_string_lossy().replace(".c", ".o");
+    // Wait for compilation to complete; the session wakes us with the result
+    let output_file = temp_file_path.with_extension("o");
     
-    let mut timeout_counter = 0;
-    loop {
-        std::thread::sleep(std::time::Duration::from_millis(100));
-        timeout_counter += 1;
-        
-        if timeout_counter > 100 { // 10 second timeout
-            println!("[Server] Timeout waiting for compilation of {}", filename);
-            return Err(io::Error::new(
-                io::ErrorKind::TimedOut,
-                "Compilation timeout",
-            ));
-        }
-        
-        let results_guard = results.lock().unwrap();
-        if let Some((_, success, log)) = results_guard
-            .iter()
-            .find(|(path, _, _)| path == &temp_file_str)
-        {
-            // Build complete! Send result back
-            let mut response_payload = Vec::new();
-            
-            if *success {
-                // Read .o file
-                match fs::read(&output_file) {
-                    Ok(obj_contents) => {
-                        response_payload.push(1); // Success
-                        let output_filename = filename.replace(".c", ".o");
-                        let output_filename_bytes = output_filename.as_bytes();
-                        response_payload.extend_from_slice(&(output_filename_bytes.len() as u32).to_be_bytes());
-                        response_payload.extend_from_slice(output_filename_bytes);
-                        response_payload.extend_from_slice(&obj_contents);
-                        
-                        println!("[Server] Sending compiled .o file for {} back to client", filename);
-                    }
-                    Err(e) => {
-                        response_payload.push(0); // Failure
-                        let error_msg = format!("Failed to read .o file: {}", e);
-                        let filename_bytes = filename.as_bytes();
-                        response_payload.extend_from_slice(&(filename_bytes.len() as u32).to_be_bytes());
-                        response_payload.extend_from_slice(filename_bytes);
-                        response_payload.extend_from_slice(error_msg.as_bytes());
-                    }
-                }
-            } else {
-                // Compilation failed
-                response_payload.push(0); // Failure
-                let filename_bytes = filename.as_bytes();
-                response_payload.extend_from_slice(&(filename_bytes.len() as u32).to_be_bytes());
-                response_payload.extend_from_slice(filename_bytes);
-                response_payload.extend_from_slice(log.as_bytes());
+    // Two separate limits: how long a job may wait for a worker, and how long
+    // it may take once a worker has it
+    let mut dispatched = false;
+    let result = loop {
+        let limit = if dispatched {
+            // The worker enforces the execution limit; give it a little
+            // slack to report back
+            config::get_exec_timeout() + EXEC_GRACE
+        } else {
+            config::get_queue_timeout()
+        };
+        match done.recv_timeout(limit) {
+            Ok(JobEvent::Finished(result)) => break Ok(result),
+            // Its worker was lost, so it waits in the queue all over again
+            Ok(JobEvent::Requeued) => dispatched = false,
+            Err(_) if dispatched => {
+                break Err(format!("No result from the worker for {}", filename));
             }
-            
-            let response = Message::new(OpCode::FileResult, response_payload);
-            stream.write_all(&response.serialize())?;
-            
-            // Cleanup
-            fs::remove_file(&temp_file_path).ok();
-            if *success {
-                fs::remove_file(&output_file).ok();
+            Err(_) => {
+                if queue.lock().unwrap().remove(job_id) {
+                    break Err(format!(
+                        "Waited more than {} s in the queue",
+                        config::get_queue_timeout().as_secs()
+                    ));
+                }
+                dispatched = true;
             }
-            
-            break;
         }
-        drop(results_guard);
-    }
+    };
     
-    Ok(())
+    let result = match result {
+        Ok(result) => result,
+        Err(error_msg) => {
+            println!("[Server] Timeout: {}", error_msg);
+            return file_result(Status::TimedOut, &filename, error_msg.as_bytes());
+        }
+    };
+    
+    // Build complete! The workspace is removed when it goes out of scope
+    if result.status != Status::Succeeded {
+        // Compilation failed or was killed
+        return file_result(result.status, &filename, result.log.as_bytes());
+    }
+    // Moved out of the workspace, so it outlives the job while it is sent
+    match transfers::keep(&output_file, false) {
+        Ok((file, size, transfer)) => {
+            println!("[Server] Sending compiled .o file for {} back to client", filename);
+            let result = FileResult { status: Status::Succeeded, name: output_filename };
+            (result, ReplyBody::Object { file, size, transfer })
+        }
+        Err(e) => {
+            let error_msg = format!("Failed to read .o file: {}", e);
+            file_result(Status::Failed, &filename, error_msg.as_bytes())
+        }
+    }
+}
+
+// What follows a FileResult: the error message, or the object file.
+enum ReplyBody {
+    Log(Vec<u8>),
+    Object { file: File, size: u64, transfer: String },
+}
+
+fn send_reply_body(stream: &mut Connection, body: ReplyBody) -> io::Result<()> {
+    match body {
+        ReplyBody::Log(log) => send_bytes(stream, &log),
+        ReplyBody::Object { mut file, size, transfer } => send_body(stream, &mut file, size, 0, &transfer),
+    }
+}
+
+// A result without an object. The name is the source's.
+fn file_result(status: Status, name: &str, log: &[u8]) -> (FileResult, ReplyBody) {
+    let result = FileResult {
+        status,
+        name: name.to_string(),
+    };
+    (result, ReplyBody::Log(log.to_vec()))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::net::{TcpListener, TcpStream};
+
+    use super::*;
+    use crate::utils::handshake::{PROTOCOL_VERSION, WorkerInfo};
+    use crate::utils::protocol::{Submission, read_body};
+
+    fn worker_with_gcc() -> WorkerRegistry {
+        let info = WorkerInfo {
+            protocol_version: PROTOCOL_VERSION,
+            dbs_version: "test".to_string(),
+            id: "fake".to_string(),
+            os: "linux".to_string(),
+            arch: "x86_64".to_string(),
+            cores: 1,
+            compilers: vec![("gcc".to_string(), "gcc (test) 1.0".to_string())],
+            compression: Vec::new(),
+            secret: String::new(),
+        };
+        Arc::new(Mutex::new(HashMap::from([("fake".to_string(), info)])))
+    }
+
+    #[test]
+    fn batch_results_go_out_as_files_finish_and_name_their_file() {
+        const FILES: u32 = 3;
+        let (queue, jobs) = (Arc::new(Mutex::new(TaskQueue::new())), Arc::new(JobTable::new()));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let server_end = Connection::plain(listener.accept().unwrap().0);
+
+        // Stands in for the workers: finishes the files last one first, each
+        // object made from its source
+        let worker = {
+            let (queue, jobs) = (Arc::clone(&queue), Arc::clone(&jobs));
+            thread::spawn(move || {
+                while queue.lock().unwrap().len() < FILES as usize {
+                    thread::sleep(Duration::from_millis(10));
+                }
+                let mut tasks: Vec<Task> = std::iter::from_fn(|| queue.lock().unwrap().take(|_| true)).collect();
+                tasks.sort_by_key(|task| Path::new(&task.path).file_name().map(|name| name.to_owned()));
+                for task in tasks.into_iter().rev() {
+                    let source = fs::read_to_string(&task.path).unwrap();
+                    fs::write(Path::new(&task.path).with_extension("o"), format!("object of {}", source)).unwrap();
+                    jobs.complete(task.id, Status::Succeeded, "OK".to_string());
+                    thread::sleep(Duration::from_millis(100));
+                }
+            })
+        };
+        let session = {
+            let (queue, jobs) = (Arc::clone(&queue), Arc::clone(&jobs));
+            let names = (0..FILES).map(|i| format!("f{}.c", i)).collect();
+            let manifest = SubmitBatch { priority: Priority::Normal, names }.to_message();
+            thread::spawn(move || handle_batch_session(server_end, manifest, queue, jobs, worker_with_gcc()))
+        };
+
+        for index in 0..FILES {
+            let submission = Submission {
+                filename: format!("f{}.c", index),
+                compiler: "gcc".to_string(),
+                ..Default::default()
+            };
+            BatchFile { index, submission }.send(&mut client).unwrap();
+            send_bytes(&mut client, format!("source {}", index).as_bytes()).unwrap();
+        }
+        BatchEnd { error: None }.send(&mut client).unwrap();
+
+        let mut order = Vec::new();
+        for _ in 0..FILES {
+            let result = BatchResult::from_message(&Message::read(&mut client).unwrap()).unwrap();
+            let object = read_body(&mut client).unwrap();
+            assert_eq!(result.result.status, Status::Succeeded);
+            assert_eq!(result.result.name, format!("f{}.o", result.index));
+            assert_eq!(object, format!("object of source {}", result.index).into_bytes());
+            order.push(result.index);
+        }
+        assert_eq!(order, [2, 1, 0]);
+        assert_eq!(BatchEnd::from_message(&Message::read(&mut client).unwrap()).unwrap(), BatchEnd { error: None });
+
+        worker.join().unwrap();
+        session.join().unwrap().unwrap();
+    }
 }
@@ -0,0 +1,141 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+
+use crate::utils::protocol::Status;
+
+pub type JobId = u64;
+
+// Outcome of one compile job.
+#[derive(Debug, Clone)]
+pub struct JobResult {
+    pub status: Status,
+    // Compiler output on failure, "OK" on success.
+    pub log: String,
+}
+
+// What whoever waits on a job hears about it.
+#[derive(Debug, Clone)]
+pub enum JobEvent {
+    // Its worker was lost and it is back in the queue.
+    Requeued,
+    Finished(JobResult),
+}
+
+// Jobs that have been submitted but not yet completed. Each job gets its own
+// channel, so whoever waits on it is woken as soon as its result is in, and
+// the entry disappears once the result has been handed over.
+#[derive(Default)]
+pub struct JobTable {
+    next_id: AtomicU64,
+    waiting: Mutex<HashMap<JobId, Sender<JobEvent>>>,
+}
+
+impl JobTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Open a new job. The receiver yields its result exactly once, as the
+    // last event.
+    pub fn register(&self) -> (JobId, Receiver<JobEvent>) {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let (tx, rx) = mpsc::channel();
+        self.waiting.lock().unwrap().insert(id, tx);
+        (id, rx)
+    }
+
+    // Deliver a result. Jobs nobody waits for any more are silently dropped.
+    pub fn complete(&self, id: JobId, status: Status, log: String) {
+        if let Some(tx) = self.waiting.lock().unwrap().remove(&id) {
+            tx.send(JobEvent::Finished(JobResult { status, log })).ok();
+        }
+    }
+
+    // Stop waiting for a job, e.g. because its client gave up.
+    pub fn cancel(&self, id: JobId) {
+        self.waiting.lock().unwrap().remove(&id);
+    }
+
+    // Tell the owner its job went back into the queue. Returns false if
+    // nobody waits for it any more.
+    pub fn requeued(&self, id: JobId) -> bool {
+        match self.waiting.lock().unwrap().get(&id) {
+            Some(tx) => tx.send(JobEvent::Requeued).is_ok(),
+            None => false,
+        }
+    }
+
+    // Whether anyone still waits for this job's result.
+    pub fn is_waiting(&self, id: JobId) -> bool {
+        self.waiting.lock().unwrap().contains_key(&id)
+    }
+
+    // Number of jobs still waiting for a result.
+    pub fn pending(&self) -> usize {
+        self.waiting.lock().unwrap().len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn finished(event: JobEvent) -> JobResult {
+        match event {
+            JobEvent::Finished(result) => result,
+            JobEvent::Requeued => panic!("expected a result, got Requeued"),
+        }
+    }
+
+    #[test]
+    fn each_job_hears_only_its_own_result() {
+        let jobs = JobTable::new();
+        let (first, first_rx) = jobs.register();
+        let (second, second_rx) = jobs.register();
+        assert_ne!(first, second);
+        assert_eq!(jobs.pending(), 2);
+
+        jobs.complete(second, Status::Failed, "error: x".to_string());
+        assert!(first_rx.try_recv().is_err());
+        let result = finished(second_rx.recv().unwrap());
+        assert_eq!((result.status, result.log.as_str()), (Status::Failed, "error: x"));
+
+        jobs.complete(first, Status::Succeeded, "OK".to_string());
+        assert_eq!(finished(first_rx.recv().unwrap()).status, Status::Succeeded);
+        assert_eq!(jobs.pending(), 0);
+    }
+
+    #[test]
+    fn result_is_the_last_event() {
+        let jobs = JobTable::new();
+        let (id, rx) = jobs.register();
+        assert!(jobs.requeued(id));
+        assert!(jobs.is_waiting(id));
+        jobs.complete(id, Status::Succeeded, "OK".to_string());
+
+        assert!(matches!(rx.recv().unwrap(), JobEvent::Requeued));
+        assert_eq!(finished(rx.recv().unwrap()).status, Status::Succeeded);
+        // The sender went away with the table entry
+        assert!(rx.recv().is_err());
+        assert!(!jobs.is_waiting(id));
+        assert!(!jobs.requeued(id));
+    }
+
+    #[test]
+    fn cancelled_and_abandoned_jobs_are_dropped() {
+        let jobs = JobTable::new();
+        let (cancelled, cancelled_rx) = jobs.register();
+        jobs.cancel(cancelled);
+        jobs.complete(cancelled, Status::Succeeded, "OK".to_string());
+        assert!(cancelled_rx.recv().is_err());
+
+        // A waiter that hung up can't be told about a requeue
+        let (abandoned, abandoned_rx) = jobs.register();
+        drop(abandoned_rx);
+        assert!(!jobs.requeued(abandoned));
+        jobs.complete(abandoned, Status::Succeeded, "OK".to_string());
+        assert_eq!(jobs.pending(), 0);
+    }
+}
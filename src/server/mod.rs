@@ -1,78 +1,74 @@
+mod cache;
 mod client_handler;
+pub mod jobs;
+pub mod queue;
 pub mod session;
+mod supervisor;
+pub mod task;
+mod transfers;
+mod workspace;
 
-use std::env;
-use std::net::TcpListener;
-use std::process::Command;
+use std::collections::HashMap;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
 use std::thread;
 
-use client_handler::handle_client_session;
-use session::handle_worker_session;
+use client_handler::{handle_batch_session, handle_client_session, handle_resume};
+use jobs::JobTable;
+use session::{WorkerRegistry, handle_worker_session};
+use supervisor::Supervisor;
+use queue::TaskQueue;
 
 use crate::utils::config;
+use crate::utils::net::Connection;
+use crate::utils::protocol::{Message, OpCode};
 
-// Server that accepts client file submissions
-pub fn server_node() {
+// Server that accepts client file submissions. Objects are cached in
+// `cache_dir` up to `cache_bytes` unless it is None.
+pub fn server_node(cache_dir: Option<PathBuf>, cache_bytes: u64) {
     let server_addr = config::get_server_addr();
     
     println!("[Server] Starting file submission server on {}", server_addr);
+    println!("[Server] Default compiler: {}", config::get_default_compiler());
     println!("[Server] Clients can submit files for compilation");
     
-    let listener = TcpListener::bind(server_addr).expect("Bind failed");
-    
-    // Shared queue and results (empty initially, filled by clients)
-    let queue: Arc<Mutex<Vec<String>>> = Arc::new(Mutex::new(Vec::new()));
-    let results: Arc<Mutex<Vec<(String, bool, String)>>> = Arc::new(Mutex::new(Vec::new()));
+    if let Some(dir) = cache_dir
+        && let Err(e) = cache::init(dir, cache_bytes)
+    {
+        eprintln!("[Server] Cache disabled: {}", e);
+    }
     
-    // Spawn local workers
-    let worker_count = config::get_worker_count();
-    let current_exe = env::current_exe().unwrap();
-    let mut _children = Vec::new();
+    workspace::clear_stale();
+    transfers::clear_stale();
     
-    for i in 0..worker_count {
-        println!("[Server] Starting worker #{}", i);
-        let child = Command::new(&current_exe)
-            .arg("worker")
-            .arg(i.to_string())
-            .spawn()
-            .expect("Failed to spawn worker");
-        _children.push(child);
-    }
+    let listener = TcpListener::bind(server_addr).expect("Bind failed");
     
-    // Accept worker connections first
-    println!("[Server] Waiting for {} workers to connect...", worker_count);
-    let mut worker_handles = Vec::new();
+    // Shared queue and job table (empty initially, filled by clients)
+    let queue = Arc::new(Mutex::new(TaskQueue::new()));
+    let jobs = Arc::new(JobTable::new());
+    let workers: WorkerRegistry = Arc::new(Mutex::new(HashMap::new()));
     
-    for _ in 0..worker_count {
-        let (stream, addr) = listener.accept().unwrap();
-        println!("[Server] Worker connected from {}", addr);
-        
-        let q = Arc::clone(&queue);
-        let r = Arc::clone(&results);
-        
-        let handle = thread::spawn(move || {
-            handle_worker_session(stream, q, r);
-        });
-        worker_handles.push(handle);
-    }
+    // Spawn local workers and keep them running. They join through the same
+    // listener as remote workers started with `dbs worker --server <addr>`.
+    let supervisor = Supervisor::start(config::get_worker_count());
     
-    println!("[Server] All workers connected. Ready to accept client submissions.");
+    println!(
+        "[Server] Ready to accept workers and client submissions ({}/{} local workers running).",
+        supervisor.live(),
+        supervisor.total()
+    );
     
-    // Accept client connections
+    // Accept connections. Workers and clients are told apart by the opcode
+    // of their first message, so either may connect at any time.
     loop {
         match listener.accept() {
             Ok((stream, addr)) => {
-                println!("[Server] Client connected from {}", addr);
-                
                 let q = Arc::clone(&queue);
-                let r = Arc::clone(&results);
+                let j = Arc::clone(&jobs);
+                let w = Arc::clone(&workers);
                 
-                thread::spawn(move || {
-                    if let Err(e) = handle_client_session(stream, q, r) {
-                        eprintln!("[Server] Client error: {}", e);
-                    }
-                });
+                thread::spawn(move || handle_connection(stream, addr, q, j, w));
             }
             Err(e) => {
                 eprintln!("[Server] Connection error: {}", e);
@@ -80,3 +76,54 @@ pub fn server_node() {
         }
     }
 }
+
+fn handle_connection(
+    tcp: TcpStream,
+    addr: SocketAddr,
+    queue: Arc<Mutex<TaskQueue>>,
+    jobs: Arc<JobTable>,
+    workers: WorkerRegistry,
+) {
+    let mut stream = match Connection::accepted(tcp) {
+        Ok(stream) => stream,
+        Err(e) => {
+            eprintln!("[Server] Refused connection from {}: {}", addr, e);
+            return;
+        }
+    };
+    let first = match Message::read(&mut stream) {
+        Ok(msg) => msg,
+        Err(e) => {
+            eprintln!("[Server] Failed to read from {}: {}", addr, e);
+            return;
+        }
+    };
+    
+    match first.op {
+        OpCode::Hello => {
+            println!("[Server] Worker connected from {}", addr);
+            handle_worker_session(stream, first, queue, jobs, workers, false);
+            println!("[Server] Worker {} left", addr);
+        }
+        OpCode::SubmitFile => {
+            println!("[Server] Client connected from {}", addr);
+            if let Err(e) = handle_client_session(stream, first, queue, jobs, workers) {
+                eprintln!("[Server] Client error: {}", e);
+            }
+        }
+        OpCode::SubmitBatch => {
+            println!("[Server] Client connected from {}", addr);
+            if let Err(e) = handle_batch_session(stream, first, queue, jobs, workers) {
+                eprintln!("[Server] Client error: {}", e);
+            }
+        }
+        OpCode::Resume => {
+            if let Err(e) = handle_resume(stream, first) {
+                eprintln!("[Server] Client error: {}", e);
+            }
+        }
+        op => {
+            eprintln!("[Server] Unexpected {:?} from {}, closing connection", op, addr);
+        }
+    }
+}
@@ -0,0 +1,138 @@
+use std::collections::HashMap;
+
+use super::jobs::JobId;
+use super::task::Task;
+
+// Tasks waiting for a worker.
+//
+// Higher priorities always go first. Within a priority, clients take turns:
+// the next task comes from whichever client was served longest ago, so one
+// big submission can't hold every worker while others wait. A client's own
+// tasks run in the order they were submitted.
+#[derive(Default)]
+pub struct TaskQueue {
+    entries: Vec<Entry>,
+    next_seq: u64,
+    // When each client last had a task handed out, in `next_seq` ticks.
+    last_served: HashMap<String, u64>,
+}
+
+struct Entry {
+    seq: u64,
+    task: Task,
+}
+
+impl TaskQueue {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, task: Task) {
+        self.next_seq += 1;
+        self.entries.push(Entry { seq: self.next_seq, task });
+    }
+
+    // Put back a task that was already handed out. It goes ahead of
+    // everything its client submitted later.
+    pub fn requeue(&mut self, task: Task) {
+        self.entries.push(Entry { seq: 0, task });
+    }
+
+    // Hand out the next task that `accept` agrees to, if any.
+    pub fn take(&mut self, accept: impl Fn(&Task) -> bool) -> Option<Task> {
+        let candidates = || self.entries.iter().enumerate().filter(|(_, e)| accept(&e.task));
+
+        let priority = candidates().map(|(_, e)| e.task.priority).max()?;
+        let (index, _) = candidates()
+            .filter(|(_, e)| e.task.priority == priority)
+            .min_by_key(|(_, e)| (self.last_served.get(&e.task.owner).copied().unwrap_or(0), e.seq))?;
+
+        let entry = self.entries.remove(index);
+        self.next_seq += 1;
+        self.last_served.insert(entry.task.owner.clone(), self.next_seq);
+        Some(entry.task)
+    }
+
+    // Drop a task that is still waiting. Returns false if it wasn't queued.
+    pub fn remove(&mut self, id: JobId) -> bool {
+        let before = self.entries.len();
+        self.entries.retain(|e| e.task.id != id);
+        self.entries.len() != before
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Task> {
+        self.entries.iter().map(|e| &e.task)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::protocol::Priority;
+
+    fn task(id: JobId, owner: &str, priority: Priority) -> Task {
+        let mut task = Task::new(id, format!("{}.c", id), "gcc".to_string(), Vec::new());
+        task.owner = owner.to_string();
+        task.priority = priority;
+        task
+    }
+
+    fn drain(queue: &mut TaskQueue) -> Vec<JobId> {
+        std::iter::from_fn(|| queue.take(|_| true)).map(|t| t.id).collect()
+    }
+
+    #[test]
+    fn single_client_is_first_in_first_out() {
+        let mut queue = TaskQueue::new();
+        for id in 1..=4 {
+            queue.push(task(id, "a", Priority::Normal));
+        }
+        assert_eq!(drain(&mut queue), vec![1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn higher_priority_goes_first() {
+        let mut queue = TaskQueue::new();
+        queue.push(task(1, "a", Priority::Low));
+        queue.push(task(2, "a", Priority::Normal));
+        queue.push(task(3, "a", Priority::High));
+        queue.push(task(4, "a", Priority::Normal));
+        assert_eq!(drain(&mut queue), vec![3, 2, 4, 1]);
+    }
+
+    #[test]
+    fn clients_take_turns() {
+        let mut queue = TaskQueue::new();
+        for id in 1..=4 {
+            queue.push(task(id, "big", Priority::Normal));
+        }
+        queue.push(task(5, "small", Priority::Normal));
+        queue.push(task(6, "small", Priority::Normal));
+        assert_eq!(drain(&mut queue), vec![1, 5, 2, 6, 3, 4]);
+    }
+
+    #[test]
+    fn requeued_task_runs_before_later_ones() {
+        let mut queue = TaskQueue::new();
+        queue.push(task(1, "a", Priority::Normal));
+        queue.push(task(2, "a", Priority::Normal));
+        let first = queue.take(|_| true).unwrap();
+        queue.requeue(first);
+        assert_eq!(drain(&mut queue), vec![1, 2]);
+    }
+
+    #[test]
+    fn take_skips_tasks_the_worker_cannot_run() {
+        let mut queue = TaskQueue::new();
+        queue.push(task(1, "a", Priority::High));
+        queue.push(task(2, "a", Priority::Normal));
+        assert_eq!(queue.take(|t| t.id == 2).map(|t| t.id), Some(2));
+        assert!(queue.remove(1));
+        assert!(!queue.remove(1));
+        assert_eq!(queue.len(), 0);
+    }
+}
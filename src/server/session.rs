@@ -1,56 +1,449 @@
-use std::io::Write;
-use std::net::TcpStream;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufReader, ErrorKind};
+use std::net::Shutdown;
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
-use crate::utils::protocol::{Message, OpCode};
+use super::cache::{self, cache_key, task_digest};
+use super::jobs::JobTable;
+use super::queue::TaskQueue;
+use super::task::Task;
+use crate::utils::compression::{Compression, negotiate};
+use crate::utils::handshake::{WorkerInfo, validate_worker};
+use crate::utils::config;
+use crate::utils::depfile;
+use crate::utils::net::{self, Connection};
+use crate::utils::protocol::{
+    self, Heartbeat, Link, Message, OpCode, Payload, Status, TaskDef, TaskResult, Welcome, begin_body,
+    is_integrity_error, part_path, read_body, receive_body, send_body, verify_body,
+};
 
-// Handle communication with a single worker
+// Live workers keyed by their peer address.
+pub type WorkerRegistry = Arc<Mutex<HashMap<String, WorkerInfo>>>;
+
+// Why a task came back from a worker without a result.
+enum Lost {
+    // The worker disconnected or went silent
+    Worker(String),
+    // Its source or object failed an integrity check on the way; the
+    // connection is still fine
+    Corrupted(String),
+}
+
+impl From<String> for Lost {
+    fn from(reason: String) -> Self {
+        Lost::Worker(reason)
+    }
+}
+
+impl From<io::Error> for Lost {
+    fn from(e: io::Error) -> Self {
+        if is_integrity_error(&e) {
+            Lost::Corrupted(e.to_string())
+        } else {
+            Lost::Worker(e.to_string())
+        }
+    }
+}
+
+// Handle communication with a single worker whose Hello has already been read.
+//
+// With `exit_when_drained` set (a fixed workload), the worker is told to shut
+// down once the queue holds nothing it can compile and no other task is still
+// in flight. The long-running server keeps its workers forever.
 pub fn handle_worker_session(
-    mut stream: TcpStream,
-    queue: Arc<Mutex<Vec<String>>>,
-    results: Arc<Mutex<Vec<(String, bool, String)>>>,
+    mut stream: Connection,
+    hello: Message,
+    queue: Arc<Mutex<TaskQueue>>,
+    jobs: Arc<JobTable>,
+    workers: WorkerRegistry,
+    exit_when_drained: bool,
 ) {
-    if let Ok(msg) = Message::read(&mut stream) {
-        if msg.op != OpCode::Hello {
+    if hello.op != OpCode::Hello {
+        return;
+    }
+
+    // A fixed workload only wants workers that can compile some of it
+    let needed: Vec<String> = if exit_when_drained {
+        let mut compilers: Vec<String> = queue.lock().unwrap().iter().map(|t| t.compiler.clone()).collect();
+        compilers.sort();
+        compilers.dedup();
+        compilers
+    } else {
+        Vec::new()
+    };
+
+    let verdict = WorkerInfo::from_message(&hello)
+        .map_err(|e| e.to_string())
+        .and_then(|info| {
+            // Wherever it connects from, a worker needs a certificate unless
+            // it is one this server started
+            if net::workers_need_certificate() && !stream.peer_certified() && !net::is_local_worker(&info.secret) {
+                return Err("worker has no cluster certificate".to_string());
+            }
+            validate_worker(&info, &needed)?;
+            Ok(info)
+        });
+    let welcome = Welcome {
+        rejection: verdict.as_ref().err().cloned(),
+        compression: verdict.as_ref().map_or(Compression::None, |info| negotiate(&info.compression)),
+    };
+    if welcome.send(&mut stream).is_err() {
+        return;
+    }
+    let info = match verdict {
+        Ok(info) => info,
+        Err(reason) => {
+            eprintln!("[Session] Rejected worker: {}", reason);
             return;
         }
-    }
+    };
+    println!(
+        "[Session] Worker {} joined: {}, compression: {}",
+        info.id,
+        info.summary(),
+        welcome.compression.name()
+    );
+
+    let key = stream
+        .peer_addr()
+        .map(|a| a.to_string())
+        .unwrap_or_else(|_| info.id.clone());
+    workers.lock().unwrap().insert(key.clone(), info.clone());
+    let mut stream = Link::new(stream, welcome.compression);
 
+    let mut last_ping = Instant::now();
     loop {
-        let task_opt = {
-            let mut q = queue.lock().unwrap();
-            q.pop()
-        };
+        // Only take work this worker has a compiler for
+        let task_opt = queue.lock().unwrap().take(|t| info.supports(&t.compiler));
 
         match task_opt {
-            Some(filepath) => {
-                // Send Filename as bytes
-                let req = Message::new(OpCode::TaskDef, filepath.clone().into_bytes());
-                if stream.write_all(&req.serialize()).is_err() {
+            // Its client gave up; compiling it would be for nobody
+            Some(task) if !jobs.is_waiting(task.id) => {}
+            Some(task) => {
+                match run_task(&mut stream, &task, &info, &jobs, config::HEARTBEAT_TIMEOUT) {
+                    Ok(()) => {}
+                    Err(Lost::Corrupted(reason)) => {
+                        eprintln!("[Session] {} on worker {}: {}", task.path, info.id, reason);
+                        requeue_or_fail(task, &reason, &queue, &jobs);
+                    }
+                    Err(Lost::Worker(reason)) => {
+                        eprintln!("[Session] Lost worker {} while compiling {}: {}", info.id, task.path, reason);
+                        // Make sure a hung worker notices it has been dropped
+                        stream.get_ref().shutdown(Shutdown::Both).ok();
+                        requeue_or_fail(task, &format!("Worker lost: {}", reason), &queue, &jobs);
+                        break;
+                    }
+                }
+                last_ping = Instant::now();
+            }
+            None => {
+                // Nothing left that this worker can compile and nothing in
+                // flight that might still fail over to it
+                if exit_when_drained && jobs.pending() <= queue.lock().unwrap().len() {
+                    protocol::Shutdown.send(&mut stream).ok();
                     break;
                 }
 
-                // Wait for Result
-                let res_msg = match Message::read(&mut stream) {
-                    Ok(m) => m,
-                    Err(_) => break,
-                };
-
-                if res_msg.op == OpCode::TaskResult {
-                    // Protocol: [1 byte status] [rest is msg string]
-                    if !res_msg.payload.is_empty() {
-                        let success = res_msg.payload[0] == 1;
-                        let out_msg = String::from_utf8_lossy(&res_msg.payload[1..]).to_string();
-
-                        let mut r_guard = results.lock().unwrap();
-                        r_guard.push((filepath, success, out_msg));
+                // Writing to a worker that went away fails, which is how an
+                // idle worker's disconnect is noticed
+                if last_ping.elapsed() >= config::HEARTBEAT_INTERVAL {
+                    if Heartbeat.send(&mut stream).is_err() {
+                        eprintln!("[Session] Worker {} went away", info.id);
+                        break;
                     }
+                    last_ping = Instant::now();
                 }
-            }
-            None => {
+
                 // Queue is empty, wait a bit and try again
-                std::thread::sleep(std::time::Duration::from_millis(100));
+                std::thread::sleep(Duration::from_millis(100));
+            }
+        }
+    }
+
+    workers.lock().unwrap().remove(&key);
+    println!("[Session] Worker {} left: {}", info.id, stream.stats().summary());
+}
+
+// Hand a task that came back without a result back to the queue, unless it
+// has run out of retries or nobody waits for it any more. `reason` is what
+// the client is told if it fails.
+fn requeue_or_fail(mut task: Task, reason: &str, queue: &Mutex<TaskQueue>, jobs: &JobTable) {
+    task.attempts += 1;
+    if task.attempts > config::get_max_retries() {
+        jobs.complete(
+            task.id,
+            Status::Failed,
+            format!("{} (attempt {} of {})", reason, task.attempts, task.attempts),
+        );
+        return;
+    }
+
+    // Hold the queue while telling the owner, so it can't give up on the job
+    // in between and leave it queued for nobody
+    let mut q = queue.lock().unwrap();
+    if jobs.requeued(task.id) {
+        println!("[Session] Requeueing {} (retry {} of {})", task.path, task.attempts, config::get_max_retries());
+        q.requeue(task);
+    }
+}
+
+// Send one task and record its result. Fails if the worker disconnected or
+// went `silence` without a word before a result came back, or if the source
+// or result was corrupted on the way; the task is then still unfinished.
+fn run_task(
+    stream: &mut Link<Connection>,
+    task: &Task,
+    info: &WorkerInfo,
+    jobs: &JobTable,
+    silence: Duration,
+) -> Result<(), Lost> {
+    // Workers may not share our filesystem, so ship the source itself
+    let (mut source, size) = match File::open(&task.path).and_then(|file| Ok((file.metadata()?.len(), file))) {
+        Ok((size, file)) => (file, size),
+        Err(e) => {
+            jobs.complete(task.id, Status::Failed, format!("Failed to read source: {}", e));
+            return Ok(());
+        }
+    };
+
+    // Objects are cached under the version of the compiler that built them
+    let key = cache::global().and_then(|_| {
+        let version = info.compiler_version(&task.compiler).unwrap_or("");
+        let digest = task_digest(task, &mut BufReader::new(&source));
+        digest.map(|digest| cache_key(&digest, &task.compiler, version))
+    });
+
+    task_def(task).send(stream).and_then(|_| send_body(stream, &mut source, size, 0, ""))?;
+
+    // Wait for Result. A busy worker sends heartbeats, so a long silence
+    // means it hung.
+    stream.get_ref().set_read_timeout(Some(silence)).ok();
+    let res_msg = loop {
+        match Message::read(stream) {
+            Ok(m) if m.op == OpCode::Heartbeat => continue,
+            Ok(m) => break m,
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                return Err(format!("no heartbeat for {} s", silence.as_secs()).into());
+            }
+            Err(e) => return Err(e.into()),
+        }
+    };
+    let result = TaskResult::from_message(&res_msg).map_err(|e| format!("bad reply: {}", e))?;
+    let outcome = if result.status == Status::Succeeded {
+        store_object(stream, task, key.as_deref()).map(|(status, log)| match status {
+            Status::Succeeded => write_depfile(task, &result.deps).map_or((status, log), |e| (Status::Failed, e)),
+            _ => (status, log),
+        })
+    } else {
+        read_body(stream)
+            .map(|_| (result.status, format!("{}{}", result.stdout, result.stderr)))
+            .map_err(Lost::from)
+    };
+    stream.get_ref().set_read_timeout(None).ok();
+    let (status, out_msg) = outcome?;
+    // The worker got a source that doesn't match what was sent
+    if status == Status::Corrupted {
+        return Err(Lost::Corrupted(out_msg));
+    }
+
+    jobs.complete(task.id, status, out_msg);
+    Ok(())
+}
+
+fn task_def(task: &Task) -> TaskDef {
+    let filename = Path::new(&task.path)
+        .file_name()
+        .and_then(|n| n.to_str())
+        .unwrap_or("unknown.c");
+
+    TaskDef {
+        filename: filename.to_string(),
+        compiler: task.compiler.clone(),
+        timeout_secs: config::get_exec_timeout().as_secs() as u32,
+        args: task.args.clone(),
+        origin: task.origin.clone(),
+        headers: task.headers.clone(),
+        deps: task.depfile.is_some(),
+    }
+}
+
+// Write the task's depfile, if it wants one: the object depends on its
+// source and on `deps`, the headers the worker's compile read. Returns
+// the reason it couldn't be written.
+fn write_depfile(task: &Task, deps: &[String]) -> Option<String> {
+    let depfile = task.depfile.as_ref()?;
+    let output = task.output.clone().unwrap_or_else(|| {
+        Path::new(&task.path).with_extension("o").to_string_lossy().to_string()
+    });
+    let prerequisites: Vec<String> = std::iter::once(task.path.clone()).chain(deps.iter().cloned()).collect();
+    fs::write(depfile, depfile::make_rule(&output, &prerequisites))
+        .err()
+        .map(|e| format!("Failed to write {}: {}", depfile, e))
+}
+
+// Receive the object that follows a successful TaskResult straight into
+// the file the task wants it in, and store it in the server's cache under
+// `key` if there is one. Fails if the connection to the worker does or the
+// object arrives corrupted.
+fn store_object(stream: &mut Link<Connection>, task: &Task, key: Option<&str>) -> Result<(Status, String), Lost> {
+    let output_path = match &task.output {
+        Some(output) => PathBuf::from(output),
+        None => Path::new(&task.path).with_extension("o"),
+    };
+    let part = part_path(&output_path);
+    let begin = begin_body(stream)?;
+
+    // Build trees often keep objects in directories nothing has created yet
+    let created = match output_path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => fs::create_dir_all(parent),
+        _ => Ok(()),
+    }
+    .and_then(|_| File::create(&part));
+    let received = match created {
+        Ok(mut file) => receive_body(stream, &begin, &mut file),
+        // Still read the object off the connection
+        Err(e) => receive_body(stream, &begin, &mut io::sink()).map(|_| Err(e)),
+    };
+    let written = match received {
+        Ok(written) => written,
+        Err(e) => {
+            fs::remove_file(&part).ok();
+            return Err(e.into());
+        }
+    };
+    // Read back what was written, so a bad disk is caught as well
+    let verified = written.and_then(|_| File::open(&part)).and_then(|mut file| verify_body(&begin, &mut file));
+    if let Err(e) = verified.and_then(|_| fs::rename(&part, &output_path)) {
+        fs::remove_file(&part).ok();
+        if is_integrity_error(&e) {
+            return Err(Lost::Corrupted(format!("Object from the worker: {}", e)));
+        }
+        return Ok((Status::Failed, format!("Failed to write {}: {}", output_path.display(), e)));
+    }
+
+    if let (Some(cache), Some(key)) = (cache::global(), key) {
+        cache.store(key, &output_path);
+    }
+    Ok((Status::Succeeded, "OK".to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::mpsc::Receiver;
+    use std::thread;
+
+    use super::*;
+    use crate::server::jobs::JobEvent;
+    use crate::utils::handshake::PROTOCOL_VERSION;
+
+    fn fake_worker() -> WorkerInfo {
+        WorkerInfo {
+            protocol_version: PROTOCOL_VERSION,
+            dbs_version: "test".to_string(),
+            id: "fake".to_string(),
+            os: "linux".to_string(),
+            arch: "x86_64".to_string(),
+            cores: 1,
+            compilers: vec![("gcc".to_string(), "gcc (test) 1.0".to_string())],
+            compression: Vec::new(),
+            secret: String::new(),
+        }
+    }
+
+    fn source_path(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("dbs-session-{}-{}.c", name, std::process::id()))
+    }
+
+    // A queued task whose source exists, and the receiver its owner waits on.
+    fn queued_task(name: &str) -> (Arc<Mutex<TaskQueue>>, Arc<JobTable>, Receiver<JobEvent>) {
+        let source = source_path(name);
+        fs::write(&source, "int main(void) { return 0; }\n").unwrap();
+        let jobs = Arc::new(JobTable::new());
+        let (id, events) = jobs.register();
+        let mut queue = TaskQueue::new();
+        queue.push(Task::new(id, source.to_string_lossy().to_string(), "gcc".to_string(), Vec::new()));
+        (Arc::new(Mutex::new(queue)), jobs, events)
+    }
+
+    // Run a session for a worker that takes one task and then hangs up.
+    fn session_with_a_worker_that_hangs_up(queue: &Arc<Mutex<TaskQueue>>, jobs: &Arc<JobTable>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut worker = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server_end, _) = listener.accept().unwrap();
+
+        let (queue, jobs) = (Arc::clone(queue), Arc::clone(jobs));
+        let session = thread::spawn(move || {
+            let workers = WorkerRegistry::default();
+            handle_worker_session(Connection::plain(server_end), fake_worker().to_message(), queue, jobs, workers, false);
+        });
+
+        let welcome = Welcome::from_message(&Message::read(&mut worker).unwrap()).unwrap();
+        assert_eq!(welcome.rejection, None);
+        assert_eq!(Message::read(&mut worker).unwrap().op, OpCode::TaskDef);
+        read_body(&mut worker).unwrap();
+        drop(worker);
+        session.join().unwrap();
+    }
+
+    #[test]
+    fn lost_worker_puts_its_task_back_until_retries_run_out() {
+        let (queue, jobs, events) = queued_task("lost");
+        for attempt in 1..=config::get_max_retries() {
+            session_with_a_worker_that_hangs_up(&queue, &jobs);
+            assert!(matches!(events.try_recv(), Ok(JobEvent::Requeued)));
+            let requeued = queue.lock().unwrap().iter().map(|t| t.attempts).collect::<Vec<_>>();
+            assert_eq!(requeued, vec![attempt]);
+        }
+
+        session_with_a_worker_that_hangs_up(&queue, &jobs);
+        assert_eq!(queue.lock().unwrap().len(), 0);
+        match events.try_recv() {
+            Ok(JobEvent::Finished(result)) => {
+                assert_eq!(result.status, Status::Failed);
+                assert!(result.log.starts_with("Worker lost: "), "{}", result.log);
+            }
+            other => panic!("expected a failure, got {:?}", other),
+        }
+        fs::remove_file(source_path("lost")).ok();
+    }
+
+    #[test]
+    fn silent_worker_is_given_up_on() {
+        let (queue, jobs, events) = queued_task("silent");
+        let task = queue.lock().unwrap().take(|_| true).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut worker = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let mut stream = Link::new(Connection::plain(listener.accept().unwrap().0), Compression::None);
+
+        // Heartbeats keep the task alive past the silence limit...
+        let beating = thread::spawn(move || {
+            Message::read(&mut worker).unwrap();
+            read_body(&mut worker).unwrap();
+            for _ in 0..3 {
+                thread::sleep(Duration::from_millis(100));
+                Heartbeat.send(&mut worker).unwrap();
             }
+            // ...and stopping them ends it, though the connection stays up
+            worker
+        });
+        let outcome = run_task(&mut stream, &task, &fake_worker(), &jobs, Duration::from_millis(250));
+        let _worker = beating.join().unwrap();
+
+        match outcome {
+            Err(Lost::Worker(reason)) => assert!(reason.starts_with("no heartbeat"), "{}", reason),
+            _ => panic!("a silent worker was not given up on"),
         }
+        assert!(events.try_recv().is_err());
+
+        requeue_or_fail(task, "Worker lost: no heartbeat", &queue, &jobs);
+        assert!(matches!(events.try_recv(), Ok(JobEvent::Requeued)));
+        assert_eq!(queue.lock().unwrap().len(), 1);
+        fs::remove_file(source_path("silent")).ok();
     }
 }
@@ -0,0 +1,187 @@
+use std::env;
+use std::io;
+use std::path::Path;
+use std::process::{Child, Command, ExitStatus};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::utils::config;
+use crate::utils::net;
+
+// Delay before restarting a worker that crashed, doubled for every crash in
+// a row up to MAX_BACKOFF.
+const INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+// A worker that ran at least this long before exiting counts as having been
+// healthy, so its crash streak starts over.
+const HEALTHY_UPTIME: Duration = Duration::from_secs(60);
+
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+// Keeps the server's local `dbs worker` processes running, restarting any
+// that exit. The server never tells its local workers to shut down, so every
+// exit is unexpected.
+pub struct Supervisor {
+    live: Arc<AtomicUsize>,
+    total: usize,
+}
+
+// One local worker position and the process currently filling it.
+struct Slot {
+    index: usize,
+    child: Option<Child>,
+    started: Instant,
+    crashes: u32,
+    restart_at: Instant,
+}
+
+impl Supervisor {
+    // Spawn `count` local workers and a thread that watches over them.
+    pub fn start(count: usize) -> Self {
+        let live = Arc::new(AtomicUsize::new(0));
+        let exe = env::current_exe().expect("Cannot locate the dbs executable");
+
+        let mut slots = Vec::new();
+        for index in 0..count {
+            println!("[Server] Starting worker #{}", index);
+            let mut slot = Slot {
+                index,
+                child: None,
+                started: Instant::now(),
+                crashes: 0,
+                restart_at: Instant::now(),
+            };
+            match spawn_worker(&exe, index) {
+                Ok(child) => {
+                    slot.child = Some(child);
+                    live.fetch_add(1, Ordering::Relaxed);
+                }
+                Err(e) => {
+                    eprintln!("[Supervisor] Failed to start worker #{}: {}", index, e);
+                    slot.crashes = 1;
+                    slot.restart_at = Instant::now() + INITIAL_BACKOFF;
+                }
+            }
+            slots.push(slot);
+        }
+
+        let watched = Arc::clone(&live);
+        thread::spawn(move || supervise(&exe, slots, &watched));
+
+        Self { live, total: count }
+    }
+
+    // Local worker processes currently running.
+    pub fn live(&self) -> usize {
+        self.live.load(Ordering::Relaxed)
+    }
+
+    pub fn total(&self) -> usize {
+        self.total
+    }
+}
+
+fn supervise(exe: &Path, mut slots: Vec<Slot>, live: &AtomicUsize) {
+    let total = slots.len();
+    loop {
+        for slot in &mut slots {
+            match &mut slot.child {
+                Some(child) => match child.try_wait() {
+                    Ok(Some(status)) => {
+                        slot.child = None;
+                        let now_live = live.fetch_sub(1, Ordering::Relaxed) - 1;
+                        if slot.started.elapsed() >= HEALTHY_UPTIME {
+                            slot.crashes = 0;
+                        }
+                        slot.crashes += 1;
+                        let delay = backoff(slot.crashes);
+                        slot.restart_at = Instant::now() + delay;
+                        eprintln!(
+                            "[Supervisor] Worker #{} {}; restarting in {:.1} s ({}/{} live)",
+                            slot.index,
+                            describe_exit(status),
+                            delay.as_secs_f32(),
+                            now_live,
+                            total
+                        );
+                    }
+                    Ok(None) => {}
+                    Err(e) => eprintln!("[Supervisor] Failed to check worker #{}: {}", slot.index, e),
+                },
+                None if Instant::now() >= slot.restart_at => match spawn_worker(exe, slot.index) {
+                    Ok(child) => {
+                        slot.child = Some(child);
+                        slot.started = Instant::now();
+                        let now_live = live.fetch_add(1, Ordering::Relaxed) + 1;
+                        println!("[Supervisor] Restarted worker #{} ({}/{} live)", slot.index, now_live, total);
+                    }
+                    Err(e) => {
+                        slot.crashes += 1;
+                        let delay = backoff(slot.crashes);
+                        slot.restart_at = Instant::now() + delay;
+                        eprintln!(
+                            "[Supervisor] Failed to restart worker #{}: {}; retrying in {:.1} s ({}/{} live)",
+                            slot.index,
+                            e,
+                            delay.as_secs_f32(),
+                            live.load(Ordering::Relaxed),
+                            total
+                        );
+                    }
+                },
+                None => {}
+            }
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+fn spawn_worker(exe: &Path, index: usize) -> io::Result<Child> {
+    Command::new(exe)
+        .arg("worker")
+        .arg(index.to_string())
+        .arg("--server")
+        .arg(config::get_server_addr())
+        // Local workers must offer the configured compiler even if it isn't
+        // one they would detect on their own
+        .arg("--compiler")
+        .arg(config::get_default_compiler())
+        // Compressing costs more than it saves on the same machine
+        .arg("--no-compression")
+        // Stands in for a cluster certificate
+        .env(net::LOCAL_WORKER_SECRET_VAR, net::local_worker_secret())
+        .spawn()
+}
+
+// INITIAL_BACKOFF for the first crash, doubling up to MAX_BACKOFF.
+fn backoff(crashes: u32) -> Duration {
+    let factor = 1u32 << crashes.saturating_sub(1).min(16);
+    INITIAL_BACKOFF.saturating_mul(factor).min(MAX_BACKOFF)
+}
+
+fn describe_exit(status: ExitStatus) -> String {
+    #[cfg(unix)]
+    if let Some(signal) = std::os::unix::process::ExitStatusExt::signal(&status) {
+        return format!("was killed by signal {}", signal);
+    }
+    match status.code() {
+        Some(code) => format!("exited with code {}", code),
+        None => format!("exited ({})", status),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_doubles_up_to_the_limit() {
+        let delays: Vec<u64> = (1..=9).map(|crashes| backoff(crashes).as_millis() as u64).collect();
+        assert_eq!(delays, vec![500, 1000, 2000, 4000, 8000, 16000, 30000, 30000, 30000]);
+        assert_eq!(backoff(0), INITIAL_BACKOFF);
+        assert_eq!(backoff(u32::MAX), MAX_BACKOFF);
+    }
+}
@@ -0,0 +1,70 @@
+use super::jobs::JobId;
+use crate::utils::protocol::Priority;
+
+// A single compile job as it waits in the queue.
+#[derive(Debug, Clone)]
+pub struct Task {
+    // Entry in the job table that receives the result.
+    pub id: JobId,
+    // Source file on the controller's disk.
+    pub path: String,
+    // Where the object is written; next to the source when None.
+    pub output: Option<String>,
+    // Where to write the make rule listing what the object was built from;
+    // workers are only asked for it when set.
+    pub depfile: Option<String>,
+    // Compiler executable the worker should run, e.g. `gcc` or `clang`.
+    pub compiler: String,
+    // Extra compiler arguments, already prepared by `flags::prepare_args`.
+    pub args: Vec<String>,
+    // For bundled sources: the source's absolute path on the submitting
+    // machine, and the headers it includes keyed the same way. The worker
+    // rebuilds that layout so relative includes resolve.
+    pub origin: String,
+    pub headers: Vec<(String, Vec<u8>)>,
+    // Times the task was dispatched to a worker that was then lost.
+    pub attempts: u32,
+    pub priority: Priority,
+    // Who submitted it, so the queue can take turns between clients.
+    pub owner: String,
+}
+
+impl Task {
+    pub fn new(id: JobId, path: String, compiler: String, args: Vec<String>) -> Self {
+        Self {
+            id,
+            path,
+            output: None,
+            depfile: None,
+            compiler,
+            args,
+            origin: String::new(),
+            headers: Vec::new(),
+            attempts: 0,
+            priority: Priority::Normal,
+            owner: String::new(),
+        }
+    }
+
+    pub fn with_output(mut self, output: String) -> Self {
+        self.output = Some(output);
+        self
+    }
+
+    pub fn with_depfile(mut self, depfile: String) -> Self {
+        self.depfile = Some(depfile);
+        self
+    }
+
+    pub fn with_owner(mut self, owner: String, priority: Priority) -> Self {
+        self.owner = owner;
+        self.priority = priority;
+        self
+    }
+
+    pub fn with_headers(mut self, origin: String, headers: Vec<(String, Vec<u8>)>) -> Self {
+        self.origin = origin;
+        self.headers = headers;
+        self
+    }
+}
@@ -0,0 +1,112 @@
+use std::collections::hash_map::RandomState;
+use std::fs::{self, File};
+use std::hash::{BuildHasher, Hasher};
+use std::io;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime};
+
+use sha2::{Digest, Sha256};
+
+// Objects on their way to clients. Each one is kept for a while after it
+// was sent, so a client whose connection broke halfway through can fetch
+// the rest with Resume.
+pub const TRANSFER_ROOT: &str = "dbs_transfers";
+
+// How long an object stays resumable.
+const KEEP_FOR: Duration = Duration::from_secs(10 * 60);
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+// Remove objects left behind by a previous run; their clients are gone.
+pub fn clear_stale() {
+    fs::remove_dir_all(TRANSFER_ROOT).ok();
+}
+
+// Keep the object at `object` for sending. A job's own object is moved,
+// one from the cache is linked (or copied) so eviction can't pull it away
+// halfway. Returns the open file, its size and its transfer id.
+pub fn keep(object: &Path, from_cache: bool) -> io::Result<(File, u64, String)> {
+    sweep();
+    fs::create_dir_all(TRANSFER_ROOT)?;
+    let id = new_id();
+    let path = Path::new(TRANSFER_ROOT).join(&id);
+    if !from_cache {
+        fs::rename(object, &path)?;
+    } else if fs::hard_link(object, &path).is_err() {
+        fs::copy(object, &path)?;
+    }
+
+    // The age counts from now, not from when the object was built
+    let file = File::options().read(true).write(true).open(&path)?;
+    file.set_modified(SystemTime::now()).ok();
+    let size = file.metadata()?.len();
+    Ok((file, size, id))
+}
+
+// Open a kept object. Returns it with its size.
+pub fn open(id: &str) -> io::Result<(File, u64)> {
+    let opened = if is_valid_id(id) {
+        File::open(Path::new(TRANSFER_ROOT).join(id))
+    } else {
+        Err(io::Error::from(io::ErrorKind::NotFound))
+    };
+    let file = opened.map_err(|e| io::Error::new(e.kind(), format!("transfer '{}' is not available: {}", id, e)))?;
+    let size = file.metadata()?.len();
+    Ok((file, size))
+}
+
+// Drop objects kept longer than KEEP_FOR.
+fn sweep() {
+    let Ok(entries) = fs::read_dir(TRANSFER_ROOT) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let expired = entry
+            .metadata()
+            .and_then(|meta| meta.modified())
+            .is_ok_and(|modified| modified.elapsed().is_ok_and(|age| age > KEEP_FOR));
+        if expired {
+            fs::remove_file(entry.path()).ok();
+        }
+    }
+}
+
+// Anyone who can connect may ask for a transfer, so ids are not just a
+// counter: the per-process random hasher seed goes into them too.
+fn new_id() -> String {
+    let mut seed = RandomState::new().build_hasher();
+    seed.write_u64(NEXT_ID.fetch_add(1, Ordering::Relaxed));
+
+    let mut hasher = Sha256::new();
+    hasher.update(seed.finish().to_be_bytes());
+    hasher.update(std::process::id().to_be_bytes());
+    let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default();
+    hasher.update(now.as_nanos().to_be_bytes());
+    hasher.finalize()[..16].iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// Ids become file names, so only accept what `new_id` makes.
+fn is_valid_id(id: &str) -> bool {
+    id.len() == 32 && id.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ids_are_unique_and_valid() {
+        let (a, b) = (new_id(), new_id());
+        assert_ne!(a, b);
+        assert!(is_valid_id(&a) && is_valid_id(&b));
+    }
+
+    #[test]
+    fn only_generated_ids_are_opened() {
+        for id in ["", "../Cargo.toml", "/etc/passwd", "0123456789abcdef0123456789abcdeg"] {
+            assert!(!is_valid_id(id), "accepted {:?}", id);
+            assert!(open(id).is_err());
+        }
+    }
+}
@@ -0,0 +1,76 @@
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+
+use super::jobs::JobId;
+
+// Root under which every submission gets its own directory.
+pub const WORKSPACE_ROOT: &str = "temp_builds";
+
+// A directory private to one job. It is removed when dropped, so the files
+// go away however the client session ends: result sent, timeout, or the
+// client disconnecting halfway.
+pub struct JobWorkspace {
+    dir: PathBuf,
+}
+
+impl JobWorkspace {
+    pub fn create(job_id: JobId) -> io::Result<Self> {
+        let dir = Path::new(WORKSPACE_ROOT).join(format!("job-{}", job_id));
+        fs::create_dir_all(&dir)?;
+        Ok(Self { dir })
+    }
+
+    pub fn file(&self, name: &str) -> PathBuf {
+        self.dir.join(name)
+    }
+}
+
+impl Drop for JobWorkspace {
+    fn drop(&mut self) {
+        fs::remove_dir_all(&self.dir).ok();
+    }
+}
+
+// Remove workspaces left behind by a previous run. Job IDs start over on
+// every start, so stale directories would otherwise be reused.
+pub fn clear_stale() {
+    fs::remove_dir_all(WORKSPACE_ROOT).ok();
+}
+
+// Accept a submitted file name only if it is a plain name: no directories,
+// no `..`, nothing absolute. Colons are refused too, since `C:x.c` is
+// drive-relative on Windows.
+pub fn sanitize_filename(name: &str) -> Result<&str, String> {
+    let mut components = Path::new(name).components();
+    match (components.next(), components.next()) {
+        (Some(Component::Normal(_)), None) if !name.contains(['/', '\\', ':']) => Ok(name),
+        _ => Err(format!("Invalid file name '{}'", name)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_plain_names() {
+        assert_eq!(sanitize_filename("main.c"), Ok("main.c"));
+        assert_eq!(sanitize_filename("my file.i"), Ok("my file.i"));
+    }
+
+    #[test]
+    fn rejects_anything_that_is_not_a_plain_name() {
+        for name in [
+            "", ".", "..", "../x.c", "a/b.c", "a\\b.c", "..\\x.c", "/etc/passwd", "/x.c", "./x.c", "x.c/",
+        ] {
+            assert!(sanitize_filename(name).is_err(), "accepted {:?}", name);
+        }
+    }
+
+    #[test]
+    fn rejects_drive_relative_names() {
+        assert!(sanitize_filename("C:x.c").is_err());
+        assert!(sanitize_filename("C:\\x.c").is_err());
+    }
+}
@@ -0,0 +1,101 @@
+use std::io::{self, Read};
+
+// Payload compression a worker and its controller can agree on in the
+// handshake.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Compression {
+    #[default]
+    None,
+    Zstd,
+}
+
+// What this build can compress with, best first.
+pub const SUPPORTED: [Compression; 1] = [Compression::Zstd];
+
+// Fast enough to keep up with a LAN, still shrinks objects a lot.
+const ZSTD_LEVEL: i32 = 3;
+
+// Payloads shorter than this aren't worth the trouble.
+const MIN_SIZE: usize = 256;
+
+impl Compression {
+    pub fn name(self) -> &'static str {
+        match self {
+            Compression::None => "none",
+            Compression::Zstd => "zstd",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "none" => Some(Compression::None),
+            "zstd" => Some(Compression::Zstd),
+            _ => None,
+        }
+    }
+
+    // The compressed payload, or None when it wouldn't be any smaller.
+    pub fn compress(self, payload: &[u8]) -> Option<Vec<u8>> {
+        if payload.len() < MIN_SIZE {
+            return None;
+        }
+        let compressed = match self {
+            Compression::None => return None,
+            Compression::Zstd => zstd::bulk::compress(payload, ZSTD_LEVEL).ok()?,
+        };
+        (compressed.len() < payload.len()).then_some(compressed)
+    }
+
+    // Fails rather than produce more than `limit` bytes. The output only
+    // grows as it is decoded, so small payloads stay small.
+    pub fn decompress(self, payload: &[u8], limit: usize) -> io::Result<Vec<u8>> {
+        let decoder = match self {
+            Compression::None => return Ok(payload.to_vec()),
+            Compression::Zstd => zstd::stream::read::Decoder::new(payload)?,
+        };
+        let mut decoded = Vec::new();
+        decoder.take(limit as u64 + 1).read_to_end(&mut decoded)?;
+        if decoded.len() > limit {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("decompresses to more than {} bytes", limit),
+            ));
+        }
+        Ok(decoded)
+    }
+}
+
+// The first of `offered` (the peer's names, best first) that we support too.
+pub fn negotiate(offered: &[String]) -> Compression {
+    offered
+        .iter()
+        .filter_map(|name| Compression::from_name(name))
+        .find(|c| SUPPORTED.contains(c))
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn zstd_round_trips_and_skips_what_does_not_shrink() {
+        let object = b"\x7fELF".repeat(1000);
+        let compressed = Compression::Zstd.compress(&object).unwrap();
+        assert!(compressed.len() < object.len() / 10);
+        assert_eq!(Compression::Zstd.decompress(&compressed, object.len()).unwrap(), object);
+        assert!(Compression::Zstd.decompress(&compressed, object.len() - 1).is_err());
+        assert!(Compression::Zstd.decompress(&compressed[..compressed.len() - 1], object.len()).is_err());
+
+        assert_eq!(Compression::Zstd.compress(b"short"), None);
+        assert_eq!(Compression::None.compress(&object), None);
+    }
+
+    #[test]
+    fn negotiation_picks_a_shared_algorithm() {
+        let offered = |names: &[&str]| names.iter().map(|n| n.to_string()).collect::<Vec<_>>();
+        assert_eq!(negotiate(&offered(&["lz4", "zstd"])), Compression::Zstd);
+        assert_eq!(negotiate(&offered(&["lz4"])), Compression::None);
+        assert_eq!(negotiate(&[]), Compression::None);
+    }
+}
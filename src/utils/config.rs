@@ -1,9 +1,29 @@
 use std::sync::OnceLock;
+use std::time::Duration;
 
 static SERVER_ADDR: OnceLock<String> = OnceLock::new();
 static WORKER_COUNT: OnceLock<usize> = OnceLock::new();
+static DEFAULT_COMPILER: OnceLock<String> = OnceLock::new();
+static QUEUE_TIMEOUT: OnceLock<Duration> = OnceLock::new();
+static EXEC_TIMEOUT: OnceLock<Duration> = OnceLock::new();
+static MAX_RETRIES: OnceLock<u32> = OnceLock::new();
 
-pub const HEADER_SIZE: usize = 5;
+pub const HEADER_SIZE: usize = 6;
+
+// Largest frame accepted from a peer. File bodies are split into chunks of
+// CHUNK_SIZE, so only message heads with many bundled headers come close.
+pub const MAX_FRAME_SIZE: usize = 32 * 1024 * 1024;
+pub const CHUNK_SIZE: usize = 1024 * 1024;
+
+// Largest body kept in memory: sources and logs. Objects go to disk.
+pub const MAX_BODY_IN_MEMORY: u64 = 256 * 1024 * 1024;
+
+// Workers send a Heartbeat this often while compiling, and the server pings
+// idle workers just as often.
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
+
+// A busy worker that stays silent this long is considered hung.
+pub const HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(15);
 
 pub fn get_server_addr() -> &'static str {
     SERVER_ADDR.get_or_init(|| "127.0.0.1:9000".to_string())
@@ -13,10 +33,45 @@ pub fn get_worker_count() -> usize {
     *WORKER_COUNT.get_or_init(|| 4)
 }
 
+pub fn get_default_compiler() -> &'static str {
+    DEFAULT_COMPILER.get_or_init(|| "gcc".to_string())
+}
+
+// How long a submission may wait for a worker before it is given up.
+pub fn get_queue_timeout() -> Duration {
+    *QUEUE_TIMEOUT.get_or_init(|| Duration::from_secs(300))
+}
+
+// How long a worker lets the compiler run before killing it.
+pub fn get_exec_timeout() -> Duration {
+    *EXEC_TIMEOUT.get_or_init(|| Duration::from_secs(120))
+}
+
+// How many times a task is handed to another worker after its worker was lost.
+pub fn get_max_retries() -> u32 {
+    *MAX_RETRIES.get_or_init(|| 2)
+}
+
 pub fn set_server_addr(addr: String) {
     SERVER_ADDR.set(addr).ok();
 }
 
 pub fn set_worker_count(count: usize) {
     WORKER_COUNT.set(count).ok();
-}
\ No newline at end of file
+}
+
+pub fn set_default_compiler(compiler: String) {
+    DEFAULT_COMPILER.set(compiler).ok();
+}
+
+pub fn set_queue_timeout(timeout: Duration) {
+    QUEUE_TIMEOUT.set(timeout).ok();
+}
+
+pub fn set_exec_timeout(timeout: Duration) {
+    EXEC_TIMEOUT.set(timeout).ok();
+}
+
+pub fn set_max_retries(retries: u32) {
+    MAX_RETRIES.set(retries).ok();
+}
@@ -0,0 +1,86 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+// Prerequisites of a make rule as printed by `-M`/`-MM`: `target: a.c b.h \`
+pub fn parse_make_rule(rule: &str) -> Vec<String> {
+    let joined = rule.replace("\\\r\n", " ").replace("\\\n", " ");
+    let Some((_, deps)) = joined.split_once(": ") else {
+        return Vec::new();
+    };
+
+    let mut prerequisites = Vec::new();
+    let mut current = String::new();
+    let mut chars = deps.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            // `\ ` is an escaped space inside a file name
+            '\\' if chars.peek() == Some(&' ') => {
+                current.push(' ');
+                chars.next();
+            }
+            c if c.is_whitespace() => {
+                if !current.is_empty() {
+                    prerequisites.push(std::mem::take(&mut current));
+                }
+            }
+            c => current.push(c),
+        }
+    }
+    if !current.is_empty() {
+        prerequisites.push(current);
+    }
+    prerequisites
+}
+
+// Prerequisites listed in a dependency file written by `-M -MF` or `-MD`.
+pub fn read(path: &Path) -> io::Result<Vec<String>> {
+    Ok(parse_make_rule(&fs::read_to_string(path)?))
+}
+
+// A make rule in the form `-MD` writes, one prerequisite per line.
+pub fn make_rule(target: &str, prerequisites: &[String]) -> String {
+    let escape = |name: &str| name.replace(' ', "\\ ");
+    let mut rule = format!("{}:", escape(target));
+    for prerequisite in prerequisites {
+        rule.push_str(" \\\n  ");
+        rule.push_str(&escape(prerequisite));
+    }
+    rule.push('\n');
+    rule
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_single_line_rule() {
+        assert_eq!(parse_make_rule("a.o: a.c a.h b.h\n"), vec!["a.c", "a.h", "b.h"]);
+    }
+
+    #[test]
+    fn joins_continuation_lines() {
+        let rule = "a.o: a.c \\\n  include/a.h \\\r\n  b.h\n";
+        assert_eq!(parse_make_rule(rule), vec!["a.c", "include/a.h", "b.h"]);
+    }
+
+    #[test]
+    fn keeps_escaped_spaces_in_names() {
+        assert_eq!(parse_make_rule("a.o: my\\ dir/a.c x.h"), vec!["my dir/a.c", "x.h"]);
+    }
+
+    #[test]
+    fn written_rules_parse_back() {
+        let prerequisites = vec!["my dir/a.c".to_string(), "/usr/include/stdio.h".to_string()];
+        let rule = make_rule("my dir/a.o", &prerequisites);
+        assert_eq!(rule, "my\\ dir/a.o: \\\n  my\\ dir/a.c \\\n  /usr/include/stdio.h\n");
+        assert_eq!(parse_make_rule(&rule), prerequisites);
+    }
+
+    #[test]
+    fn rule_without_prerequisites_is_empty() {
+        assert!(parse_make_rule("").is_empty());
+        assert!(parse_make_rule("garbage").is_empty());
+    }
+}
@@ -0,0 +1,382 @@
+use std::path::{Component, Path, PathBuf};
+
+// Options that take a path, either as the next argument or glued on (`-Iinc`).
+const PATH_OPTIONS: [&str; 4] = ["-I", "-isystem", "-iquote", "-idirafter"];
+
+// Options that take a path only as the next argument.
+const SEPARATE_PATH_OPTIONS: [&str; 2] = ["-include", "-imacros"];
+
+// dbs decides what is produced and where it goes, so these can't be passed
+// through. Matched as prefixes so glued forms (`-ofoo`, `-MFdeps.d`,
+// `--output=foo`) are caught too.
+const OUTPUT_PREFIXES: [&str; 7] = [
+    "-o",
+    "-M",
+    "--output",
+    "--dependencies",
+    "--write-dependencies",
+    "--user-dependencies",
+    "--write-user-dependencies",
+];
+const OUTPUT_OPTIONS: [&str; 4] = ["-E", "-S", "--preprocess", "--assemble"];
+
+// Options that would make a worker run or load programs of the sender's
+// choosing, forward options past these checks to the preprocessor,
+// assembler or clang's frontend (`-X...`), read option files from the
+// worker's disk, or write files at paths of the sender's choosing.
+const UNSAFE_PREFIXES: [&str; 30] = [
+    "@",
+    "--config",
+    "-wrapper",
+    "-fplugin",
+    "-fpass-plugin",
+    "-B",
+    "-specs",
+    "--specs",
+    "-Wp,",
+    "-Wa,",
+    "-X",
+    "-mllvm",
+    "--include",
+    "--imacros",
+    "-save-temps",
+    "--save-temps",
+    "-dumpdir",
+    "--dumpdir",
+    "-dumpbase",
+    "--dumpbase",
+    "-fdump-",
+    "-fopt-info",
+    "-fprofile-",
+    "-fcallgraph-info",
+    "-aux-info",
+    "-ftime-trace",
+    "-foptimization-record-file",
+    "-fcrash-diagnostics",
+    "-fmodules-cache-path",
+    "--serialize-diagnostics",
+];
+
+// Turn user-supplied compiler arguments into something a worker on another
+// machine can apply: `-c` is dropped (the worker adds it), output-controlling
+// options are refused, and relative paths are made absolute so they don't
+// depend on the worker's working directory.
+pub fn prepare_args(args: &[String]) -> Result<Vec<String>, String> {
+    let base = std::env::current_dir().map_err(|e| e.to_string())?;
+    prepare_args_in(&base, args)
+}
+
+// Same as `prepare_args`, with relative paths taken from `base` instead of
+// the current directory.
+pub fn prepare_args_in(base: &Path, args: &[String]) -> Result<Vec<String>, String> {
+    let mut prepared = Vec::new();
+    let mut iter = args.iter();
+
+    while let Some(arg) = iter.next() {
+        if arg == "-c" {
+            continue;
+        }
+        if controls_output(arg) {
+            return Err(format!("'{}' is controlled by dbs and can't be passed to the compiler", arg));
+        }
+        if is_unsafe(arg) {
+            return Err(format!("'{}' can't be passed to remote workers", arg));
+        }
+
+        if PATH_OPTIONS.contains(&arg.as_str()) || SEPARATE_PATH_OPTIONS.contains(&arg.as_str()) {
+            let value = iter
+                .next()
+                .ok_or_else(|| format!("'{}' is missing its path argument", arg))?;
+            prepared.push(arg.clone());
+            prepared.push(absolutize(base, value));
+            continue;
+        }
+
+        if let Some(opt) = PATH_OPTIONS.iter().find(|opt| arg.starts_with(*opt)) {
+            prepared.push(format!("{}{}", opt, absolutize(base, &arg[opt.len()..])));
+            continue;
+        }
+
+        if let Some(dir) = arg.strip_prefix("--sysroot=") {
+            prepared.push(format!("--sysroot={}", absolutize(base, dir)));
+            continue;
+        }
+
+        prepared.push(arg.clone());
+    }
+
+    Ok(prepared)
+}
+
+// Drop the options the preprocessor has already applied, for sources that
+// are shipped preprocessed. That includes dependency-file options: the
+// depfile is written while preprocessing.
+pub fn strip_preprocessor_args(args: &[String]) -> Vec<String> {
+    const WITH_VALUE: [&str; 11] = [
+        "-D", "-U", "-I", "-isystem", "-iquote", "-idirafter", "-include", "-imacros", "-MF", "-MT", "-MQ",
+    ];
+    const GLUED: [&str; 9] = ["-D", "-U", "-I", "-isystem", "-iquote", "-idirafter", "-MF", "-MT", "-MQ"];
+    const FLAGS: [&str; 4] = ["-MD", "-MMD", "-MP", "-MG"];
+
+    let mut kept = Vec::new();
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        if WITH_VALUE.contains(&arg.as_str()) {
+            iter.next();
+            continue;
+        }
+        if FLAGS.contains(&arg.as_str()) || GLUED.iter().any(|opt| arg.starts_with(opt)) {
+            continue;
+        }
+        kept.push(arg.clone());
+    }
+    kept
+}
+
+// Point absolute include paths at their copies inside a sandbox where shipped
+// headers were reconstructed. Paths with no copy are left alone, so system
+// include directories keep working.
+pub fn rewrite_for_sandbox(args: &[String], sandbox: &Path) -> Vec<String> {
+    map_path_args(args, |path| match sandbox_path(sandbox, path) {
+        Some(local) if local.exists() => local.to_string_lossy().to_string(),
+        _ => path.to_string(),
+    })
+}
+
+// Apply `f` to the path of every path-taking option, in either form.
+pub fn map_path_args(args: &[String], f: impl Fn(&str) -> String) -> Vec<String> {
+    let mut mapped = Vec::new();
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        if PATH_OPTIONS.contains(&arg.as_str()) || SEPARATE_PATH_OPTIONS.contains(&arg.as_str()) {
+            mapped.push(arg.clone());
+            if let Some(value) = iter.next() {
+                mapped.push(f(value));
+            }
+        } else if let Some(opt) = PATH_OPTIONS.iter().find(|opt| arg.starts_with(*opt)) {
+            mapped.push(format!("{}{}", opt, f(&arg[opt.len()..])));
+        } else if let Some(dir) = arg.strip_prefix("--sysroot=") {
+            mapped.push(format!("--sysroot={}", f(dir)));
+        } else {
+            mapped.push(arg.clone());
+        }
+    }
+    mapped
+}
+
+// Map an absolute path from the submitting machine into `sandbox`. Returns
+// None for anything that could escape it, such as `..` components.
+pub fn sandbox_path(sandbox: &Path, path: &str) -> Option<PathBuf> {
+    let mut local = sandbox.to_path_buf();
+    for component in Path::new(path).components() {
+        match component {
+            Component::Prefix(_) | Component::RootDir | Component::CurDir => {}
+            Component::Normal(part) => local.push(part),
+            Component::ParentDir => return None,
+        }
+    }
+    (local != sandbox).then_some(local)
+}
+
+// Absolute form of `path` with `.` and `..` resolved lexically, so the same
+// file always gets the same name no matter how it was reached.
+pub fn normalize_path(path: &Path) -> PathBuf {
+    let absolute = std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf());
+    let mut normalized = PathBuf::new();
+    for component in absolute.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            other => normalized.push(other),
+        }
+    }
+    normalized
+}
+
+// `path` relative to `base`, both absolute and normalized, e.g.
+// `/src/include/a.h` relative to `/src/lib` is `../include/a.h`.
+pub fn relative_path(base: &Path, path: &Path) -> PathBuf {
+    let base: Vec<Component> = base.components().collect();
+    let path: Vec<Component> = path.components().collect();
+    let common = base.iter().zip(&path).take_while(|(a, b)| a == b).count();
+
+    let mut relative = PathBuf::new();
+    for _ in common..base.len() {
+        relative.push("..");
+    }
+    for component in &path[common..] {
+        relative.push(component);
+    }
+    relative
+}
+
+// Checked by the worker before running anything: arguments come from the
+// network and must not be able to redirect output or run other programs.
+pub fn check_remote_args(args: &[String]) -> Result<(), String> {
+    for arg in args {
+        if controls_output(arg) || is_unsafe(arg) {
+            return Err(format!("argument '{}' is not allowed on a worker", arg));
+        }
+    }
+    Ok(())
+}
+
+// Files forced into the compile with -include or -imacros must be ones the
+// client shipped, never the worker's own: the compiler's diagnostics would
+// hand their contents back. Checked after `rewrite_for_sandbox`.
+pub fn check_forced_includes(args: &[String], sandbox: &Path) -> Result<(), String> {
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        if !SEPARATE_PATH_OPTIONS.contains(&arg.as_str()) {
+            continue;
+        }
+        let path = iter.next().map(String::as_str).unwrap_or_default();
+        let path_in_sandbox = Path::new(path).starts_with(sandbox)
+            && !Path::new(path).components().any(|c| c == Component::ParentDir);
+        if !path_in_sandbox {
+            return Err(format!("'{} {}' must name a header sent with the source", arg, path));
+        }
+    }
+    Ok(())
+}
+
+fn controls_output(arg: &str) -> bool {
+    OUTPUT_OPTIONS.contains(&arg) || OUTPUT_PREFIXES.iter().any(|p| arg.starts_with(p))
+}
+
+fn is_unsafe(arg: &str) -> bool {
+    // Only the separate forms of -include and -imacros have their path checked
+    let glued_include = SEPARATE_PATH_OPTIONS
+        .iter()
+        .any(|opt| arg.starts_with(opt) && arg != *opt);
+    glued_include || UNSAFE_PREFIXES.iter().any(|p| arg.starts_with(p))
+}
+
+fn absolutize(base: &Path, path: &str) -> String {
+    normalize_path(&base.join(path)).to_string_lossy().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(list: &[&str]) -> Vec<String> {
+        list.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn prepare_args_drops_c_and_absolutizes_paths() {
+        let base = std::env::current_dir().unwrap();
+        let prepared = prepare_args(&args(&["-c", "-O2", "-I", "inc", "-Iother", "-DX=1"])).unwrap();
+        assert_eq!(
+            prepared,
+            vec![
+                "-O2".to_string(),
+                "-I".to_string(),
+                base.join("inc").to_string_lossy().to_string(),
+                format!("-I{}", base.join("other").display()),
+                "-DX=1".to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn prepare_args_rejects_output_options_in_any_form() {
+        for arg in ["-o", "-ofoo.o", "-E", "-S", "-MD", "-MMD", "-MF", "-MFdeps.d", "-MTx", "--output=foo"] {
+            assert!(prepare_args(&args(&[arg])).is_err(), "accepted {}", arg);
+        }
+    }
+
+    #[test]
+    fn prepare_args_rejects_unsafe_options() {
+        for arg in ["-Wp,-MD,/tmp/evil.d", "-Xpreprocessor", "-Xclang", "@opts.txt", "--config=x.cfg", "-save-temps", "-fdump-tree-all"] {
+            assert!(prepare_args(&args(&[arg])).is_err(), "accepted {}", arg);
+        }
+    }
+
+    #[test]
+    fn check_remote_args_rejects_bypasses() {
+        for arg in [
+            "-Wp,-MD,/tmp/rv/evil.d",
+            "-Wp,-MMD,/tmp/rv/evil.d",
+            "-Xpreprocessor",
+            "-Xassembler",
+            "-Wa,-alh=/tmp/listing",
+            "@/etc/passwd",
+            "-save-temps=obj",
+            "-dumpdir",
+            "-dumpbase",
+            "-fdump-rtl-all",
+            "-MFpath",
+            "-MD",
+            "-ofoo",
+            "-fplugin=/tmp/evil.so",
+            "-B/tmp",
+            "-wrapper",
+            "--specs=/tmp/evil",
+            "-Xclang",
+            "-Xclang=-load",
+            "-Xanalyzer",
+            "-mllvm",
+            "-fpass-plugin=/tmp/evil.so",
+            "--config",
+            "--config=/tmp/evil.cfg",
+            "--config-system-dir=/tmp",
+            "-include/etc/shadow",
+            "-imacros/etc/shadow",
+            "--include=/etc/shadow",
+            "--imacros=/etc/shadow",
+            "-ftime-trace=/tmp/trace.json",
+            "-foptimization-record-file=/tmp/x",
+            "-fcrash-diagnostics-dir=/tmp",
+            "-fmodules-cache-path=/tmp",
+            "--serialize-diagnostics",
+        ] {
+            assert!(check_remote_args(&args(&[arg])).is_err(), "accepted {}", arg);
+        }
+    }
+
+    #[test]
+    fn check_remote_args_accepts_ordinary_options() {
+        let ordinary = args(&[
+            "-O2", "-g", "-Wall", "-Wextra", "-Werror=format", "-std=c11", "-DNDEBUG", "-UFOO",
+            "-I/usr/include", "-march=native", "-fPIC", "-fno-strict-aliasing", "-pedantic",
+        ]);
+        assert!(check_remote_args(&ordinary).is_ok());
+    }
+
+    #[test]
+    fn forced_includes_must_come_from_the_sandbox() {
+        let sandbox = Path::new("/scratch/tree");
+        assert!(check_forced_includes(&args(&["-include", "/scratch/tree/src/config.h", "-O2"]), sandbox).is_ok());
+        assert!(check_forced_includes(&args(&["-I", "/usr/include", "-DX"]), sandbox).is_ok());
+        for option in ["-include", "-imacros"] {
+            assert!(check_forced_includes(&args(&[option, "/etc/shadow"]), sandbox).is_err());
+            assert!(check_forced_includes(&args(&[option, "/scratch/tree/../../etc/shadow"]), sandbox).is_err());
+            assert!(check_forced_includes(&args(&[option]), sandbox).is_err());
+        }
+    }
+
+    #[test]
+    fn sandbox_path_stays_inside_the_sandbox() {
+        let sandbox = Path::new("/scratch/tree");
+        assert_eq!(
+            sandbox_path(sandbox, "/home/u/src/a.h"),
+            Some(PathBuf::from("/scratch/tree/home/u/src/a.h"))
+        );
+        assert_eq!(sandbox_path(sandbox, "/home/u/../../etc/passwd"), None);
+        assert_eq!(sandbox_path(sandbox, "../x.h"), None);
+        assert_eq!(sandbox_path(sandbox, "/"), None);
+    }
+
+    #[test]
+    fn strip_preprocessor_args_keeps_codegen_options() {
+        let stripped = strip_preprocessor_args(&args(&["-DX", "-I", "inc", "-O2", "-include", "a.h", "-g"]));
+        assert_eq!(stripped, args(&["-O2", "-g"]));
+
+        let stripped = strip_preprocessor_args(&args(&["-MMD", "-MP", "-MF", "a.d", "-MTa.o", "-MQ", "$a", "-Wall"]));
+        assert_eq!(stripped, args(&["-Wall"]));
+    }
+}
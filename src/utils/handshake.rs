@@ -0,0 +1,234 @@
+use std::io;
+use std::process::Command;
+
+use super::compression;
+use super::net::LOCAL_WORKER_SECRET_VAR;
+use super::protocol::{Decoder, Encoder, OpCode, Payload};
+
+// Bumped whenever a payload layout changes in a way older binaries can't read.
+// Workers send it in Hello, clients at the start of every SubmitFile and
+// SubmitBatch.
+pub const PROTOCOL_VERSION: u16 = 10;
+
+pub const DBS_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+// Compilers a worker probes for when it starts.
+const KNOWN_COMPILERS: [&str; 3] = ["gcc", "clang", "cc"];
+
+// What a worker tells the controller about itself in its Hello message.
+#[derive(Debug, Clone)]
+pub struct WorkerInfo {
+    pub protocol_version: u16,
+    pub dbs_version: String,
+    pub id: String,
+    pub os: String,
+    pub arch: String,
+    pub cores: u32,
+    // (executable name, first line of `--version`)
+    pub compilers: Vec<(String, String)>,
+    // Payload compression the worker can use, best first
+    pub compression: Vec<String>,
+    // What a server's own local workers prove themselves with instead of a
+    // certificate; empty for everyone else
+    pub secret: String,
+}
+
+impl WorkerInfo {
+    // Describe the machine this process is running on. `extra_compilers` are
+    // probed in addition to the usual ones, e.g. cross toolchains.
+    pub fn local(id: &str, extra_compilers: &[String]) -> Self {
+        let cores = std::thread::available_parallelism()
+            .map(|n| n.get() as u32)
+            .unwrap_or(1);
+
+        Self {
+            protocol_version: PROTOCOL_VERSION,
+            dbs_version: DBS_VERSION.to_string(),
+            id: id.to_string(),
+            os: std::env::consts::OS.to_string(),
+            arch: std::env::consts::ARCH.to_string(),
+            cores,
+            compilers: detect_compilers(extra_compilers),
+            compression: compression::SUPPORTED.iter().map(|c| c.name().to_string()).collect(),
+            secret: std::env::var(LOCAL_WORKER_SECRET_VAR).unwrap_or_default(),
+        }
+    }
+
+    pub fn supports(&self, compiler: &str) -> bool {
+        self.compilers.iter().any(|(name, _)| name == compiler)
+    }
+
+    pub fn compiler_version(&self, compiler: &str) -> Option<&str> {
+        self.compilers
+            .iter()
+            .find(|(name, _)| name == compiler)
+            .map(|(_, version)| version.as_str())
+    }
+
+    pub fn summary(&self) -> String {
+        let compilers: Vec<&str> = self.compilers.iter().map(|(name, _)| name.as_str()).collect();
+        format!(
+            "dbs {}, {}/{}, {} cores, compilers: [{}]",
+            self.dbs_version,
+            self.os,
+            self.arch,
+            self.cores,
+            compilers.join(", ")
+        )
+    }
+}
+
+// Hello payload: [2 bytes protocol version][dbs version][id][os][arch]
+//                [4 bytes cores][4 bytes compiler count]{[name][version]}
+//                [compression names][secret]
+// The version is checked first so a mismatch gets a readable reason the
+// controller can pass on to the peer.
+impl Payload for WorkerInfo {
+    const OP: OpCode = OpCode::Hello;
+
+    fn encode(&self, out: &mut Encoder) {
+        out.u16(self.protocol_version)
+            .string(&self.dbs_version)
+            .string(&self.id)
+            .string(&self.os)
+            .string(&self.arch)
+            .u32(self.cores)
+            .u32(self.compilers.len() as u32);
+        for (name, version) in &self.compilers {
+            out.string(name).string(version);
+        }
+        out.strings(&self.compression).string(&self.secret);
+    }
+
+    fn decode(input: &mut Decoder) -> io::Result<Self> {
+        let protocol_version = input.version()?;
+        let dbs_version = input.string("dbs version")?;
+        let id = input.string("id")?;
+        let os = input.string("os")?;
+        let arch = input.string("arch")?;
+        let cores = input.u32("cores")?;
+        let count = input.u32("compiler count")?;
+        let compilers = (0..count)
+            .map(|_| Ok((input.string("compiler")?, input.string("compiler version")?)))
+            .collect::<io::Result<_>>()?;
+        let compression = input.strings("compression")?;
+        let secret = input.string("secret")?;
+
+        Ok(Self {
+            protocol_version,
+            dbs_version,
+            id,
+            os,
+            arch,
+            cores,
+            compilers,
+            compression,
+            secret,
+        })
+    }
+}
+
+// Check a decoded Hello against what this controller needs. `needed` lists
+// the compilers of a fixed workload; an empty list accepts any compiler.
+pub fn validate_worker(info: &WorkerInfo, needed: &[String]) -> Result<(), String> {
+    if info.compilers.is_empty() {
+        return Err("worker has no usable C compiler".to_string());
+    }
+    if !needed.is_empty() && !needed.iter().any(|c| info.supports(c)) {
+        return Err(format!(
+            "worker offers none of the compilers this build needs: {}",
+            needed.join(", ")
+        ));
+    }
+    Ok(())
+}
+
+fn detect_compilers(extra: &[String]) -> Vec<(String, String)> {
+    let mut candidates: Vec<&str> = KNOWN_COMPILERS.to_vec();
+    for name in extra {
+        if !candidates.contains(&name.as_str()) {
+            candidates.push(name);
+        }
+    }
+
+    candidates
+        .into_iter()
+        .filter_map(|name| {
+            let out = Command::new(name).arg("--version").output().ok()?;
+            if !out.status.success() {
+                return None;
+            }
+            let version = String::from_utf8_lossy(&out.stdout)
+                .lines()
+                .next()
+                .unwrap_or("")
+                .trim()
+                .to_string();
+            Some((name.to_string(), version))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::protocol::Message;
+
+    fn sample() -> WorkerInfo {
+        WorkerInfo {
+            protocol_version: PROTOCOL_VERSION,
+            dbs_version: DBS_VERSION.to_string(),
+            id: "w1".to_string(),
+            os: "linux".to_string(),
+            arch: "x86_64".to_string(),
+            cores: 8,
+            compilers: vec![("gcc".to_string(), "gcc 13.2".to_string())],
+            compression: vec!["zstd".to_string()],
+            secret: "s3cret".to_string(),
+        }
+    }
+
+    #[test]
+    fn hello_round_trips() {
+        let decoded = WorkerInfo::from_message(&sample().to_message()).unwrap();
+        assert_eq!(decoded.id, "w1");
+        assert_eq!(decoded.cores, 8);
+        assert_eq!(decoded.compiler_version("gcc"), Some("gcc 13.2"));
+        assert!(!decoded.supports("clang"));
+        assert_eq!(compression::negotiate(&decoded.compression), compression::Compression::Zstd);
+        assert_eq!(decoded.secret, "s3cret");
+    }
+
+    #[test]
+    fn truncated_hello_is_rejected() {
+        let payload = sample().to_message().payload;
+        for len in 0..payload.len() {
+            let msg = Message::new(OpCode::Hello, payload[..len].to_vec());
+            assert!(WorkerInfo::from_message(&msg).is_err(), "accepted {} bytes", len);
+        }
+    }
+
+    #[test]
+    fn other_protocol_version_is_rejected() {
+        let mut info = sample();
+        info.protocol_version = PROTOCOL_VERSION + 1;
+        let err = WorkerInfo::from_message(&info.to_message()).unwrap_err();
+        assert!(err.to_string().contains("protocol version"));
+    }
+
+    #[test]
+    fn oversized_string_length_is_rejected() {
+        let mut payload = PROTOCOL_VERSION.to_be_bytes().to_vec();
+        payload.extend_from_slice(&u32::MAX.to_be_bytes());
+        payload.extend_from_slice(b"0.1.0");
+        assert!(WorkerInfo::from_message(&Message::new(OpCode::Hello, payload)).is_err());
+    }
+
+    #[test]
+    fn worker_without_needed_compiler_is_rejected() {
+        let info = sample();
+        assert!(validate_worker(&info, &[]).is_ok());
+        assert!(validate_worker(&info, &["gcc".to_string()]).is_ok());
+        assert!(validate_worker(&info, &["clang".to_string()]).is_err());
+    }
+}
@@ -1,2 +1,7 @@
+pub mod compression;
 pub mod config;
+pub mod depfile;
+pub mod flags;
+pub mod handshake;
+pub mod net;
 pub mod protocol;
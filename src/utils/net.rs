@@ -0,0 +1,496 @@
+use std::fs::File;
+use std::io::{self, BufReader, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::path::Path;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
+
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{ClientConfig, ClientConnection, RootCertStore, ServerConfig, ServerConnection};
+
+// TLS settings of this process, if it uses TLS at all. `serve` sets the
+// server side, `submit`, `cc` and `worker` the client side.
+static SERVER_TLS: OnceLock<ServerTls> = OnceLock::new();
+static CLIENT_TLS: OnceLock<Arc<ClientConfig>> = OnceLock::new();
+
+// First byte of every TLS connection (a handshake record). No opcode uses
+// it, so the server can take TLS and plain connections on the same port.
+const TLS_HANDSHAKE: u8 = 0x16;
+
+// Ciphertext read off the socket at a time.
+const READ_SIZE: usize = 16 * 1024;
+
+// How long a peer may take to say anything and finish the TLS handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Where the server puts the secret its local workers send in their Hello.
+// The environment rather than the command line, which other users can read.
+pub const LOCAL_WORKER_SECRET_VAR: &str = "DBS_LOCAL_WORKER_SECRET";
+static LOCAL_WORKER_SECRET: OnceLock<String> = OnceLock::new();
+
+pub struct ServerTls {
+    config: Arc<ServerConfig>,
+    // Workers must present a certificate signed by the cluster CA
+    workers_need_certificate: bool,
+}
+
+// The server's certificate and key, and the CA that signs worker
+// certificates if workers must have one.
+pub fn server_tls(cert: &Path, key: &Path, worker_ca: Option<&Path>) -> io::Result<ServerTls> {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let builder = ServerConfig::builder_with_provider(Arc::clone(&provider))
+        .with_safe_default_protocol_versions()
+        .map_err(invalid)?;
+    // Clients don't have certificates, so one is only demanded of workers,
+    // after the handshake
+    let builder = match worker_ca {
+        Some(ca) => {
+            let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(load_roots(ca)?), provider)
+                .allow_unauthenticated()
+                .build()
+                .map_err(invalid)?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let config = builder
+        .with_single_cert(load_certs(cert)?, load_key(key)?)
+        .map_err(invalid)?;
+    Ok(ServerTls {
+        config: Arc::new(config),
+        workers_need_certificate: worker_ca.is_some(),
+    })
+}
+
+// Trust servers whose certificate `ca` signed. `identity` is our own
+// certificate and key, for servers that ask for one.
+pub fn client_tls(ca: &Path, identity: Option<(&Path, &Path)>) -> io::Result<Arc<ClientConfig>> {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let builder = ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .map_err(invalid)?
+        .with_root_certificates(load_roots(ca)?);
+    let config = match identity {
+        Some((cert, key)) => builder
+            .with_client_auth_cert(load_certs(cert)?, load_key(key)?)
+            .map_err(invalid)?,
+        None => builder.with_no_client_auth(),
+    };
+    Ok(Arc::new(config))
+}
+
+pub fn set_server_tls(tls: ServerTls) {
+    SERVER_TLS.set(tls).ok();
+}
+
+pub fn set_client_tls(config: Arc<ClientConfig>) {
+    CLIENT_TLS.set(config).ok();
+}
+
+pub fn workers_need_certificate() -> bool {
+    SERVER_TLS.get().is_some_and(|tls| tls.workers_need_certificate)
+}
+
+// Secret this server's local workers are started with, made up on first use.
+pub fn local_worker_secret() -> &'static str {
+    LOCAL_WORKER_SECRET.get_or_init(|| {
+        let mut bytes = [0u8; 32];
+        rustls::crypto::ring::default_provider()
+            .secure_random
+            .fill(&mut bytes)
+            .expect("No source of randomness for the local worker secret");
+        bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    })
+}
+
+// Whether a Hello's `secret` shows it comes from one of our local workers.
+pub fn is_local_worker(secret: &str) -> bool {
+    !secret.is_empty() && LOCAL_WORKER_SECRET.get().is_some_and(|ours| ours == secret)
+}
+
+// Connect to the server at `addr`, over TLS if this process is set up for it.
+pub fn connect(addr: &str) -> io::Result<Connection> {
+    Connection::to_server(TcpStream::connect(addr)?, addr)
+}
+
+// A connection to a peer, encrypted or not. Clones share the connection, so
+// one thread can read while another writes.
+pub struct Connection {
+    tcp: TcpStream,
+    tls: Option<Arc<Tls>>,
+    // Where ciphertext is read into, kept between reads
+    ciphertext: Vec<u8>,
+}
+
+struct Tls {
+    state: Mutex<TlsState>,
+    // Held while a record is written to the socket, so records go out in
+    // the order they were encrypted
+    sending: Mutex<()>,
+}
+
+struct TlsState {
+    conn: rustls::Connection,
+    // Ciphertext read off the socket and not handed to rustls yet
+    pending: Vec<u8>,
+    eof: bool,
+}
+
+impl Connection {
+    pub fn plain(tcp: TcpStream) -> Self {
+        Self {
+            tcp,
+            tls: None,
+            ciphertext: Vec::new(),
+        }
+    }
+
+    // The client end of `tcp`, connected to `addr`.
+    pub fn to_server(tcp: TcpStream, addr: &str) -> io::Result<Self> {
+        match CLIENT_TLS.get() {
+            Some(config) => Self::within(HANDSHAKE_TIMEOUT, tcp, |tcp| Self::client_handshake(tcp, addr, config)),
+            None => Ok(Self::plain(tcp)),
+        }
+    }
+
+    // The server end of an accepted `tcp`. With TLS set up, plain
+    // connections are only taken from this machine, where the local workers
+    // run. Being local proves nothing about a worker, though: see
+    // `is_local_worker`.
+    pub fn accepted(tcp: TcpStream) -> io::Result<Self> {
+        match SERVER_TLS.get() {
+            Some(tls) => Self::within(HANDSHAKE_TIMEOUT, tcp, |tcp| Self::server_handshake(tcp, &tls.config)),
+            None => Ok(Self::plain(tcp)),
+        }
+    }
+
+    // Run `handshake` with reads limited to `limit`, so a peer that connects
+    // and then stalls can't hold on forever. The limit is lifted afterwards.
+    fn within(limit: Duration, tcp: TcpStream, handshake: impl FnOnce(TcpStream) -> io::Result<Self>) -> io::Result<Self> {
+        tcp.set_read_timeout(Some(limit))?;
+        let stream = handshake(tcp)?;
+        stream.set_read_timeout(None)?;
+        Ok(stream)
+    }
+
+    fn client_handshake(tcp: TcpStream, addr: &str, config: &Arc<ClientConfig>) -> io::Result<Self> {
+        // The certificate must name the host as it was given: a DNS name or an IP
+        let host = addr.rsplit_once(':').map_or(addr, |(host, _)| host);
+        let name = ServerName::try_from(host.trim_matches(['[', ']']).to_string())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, format!("'{}': {}", host, e)))?;
+        let conn = ClientConnection::new(Arc::clone(config), name).map_err(invalid)?;
+        Self::handshake(tcp, conn.into())
+    }
+
+    fn server_handshake(tcp: TcpStream, config: &Arc<ServerConfig>) -> io::Result<Self> {
+        let mut first = [0u8; 1];
+        if tcp.peek(&mut first)? == 1 && first[0] == TLS_HANDSHAKE {
+            let conn = ServerConnection::new(Arc::clone(config)).map_err(invalid)?;
+            return Self::handshake(tcp, conn.into());
+        }
+        if is_same_machine(tcp.peer_addr()?, tcp.local_addr()?) {
+            return Ok(Self::plain(tcp));
+        }
+        Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "this server only takes TLS connections from other machines",
+        ))
+    }
+
+    fn handshake(mut tcp: TcpStream, mut conn: rustls::Connection) -> io::Result<Self> {
+        while conn.is_handshaking() {
+            conn.complete_io(&mut tcp)?;
+        }
+        while conn.wants_write() {
+            conn.write_tls(&mut tcp)?;
+        }
+        let state = TlsState {
+            conn,
+            pending: Vec::new(),
+            eof: false,
+        };
+        Ok(Self {
+            tcp,
+            tls: Some(Arc::new(Tls {
+                state: Mutex::new(state),
+                sending: Mutex::new(()),
+            })),
+            ciphertext: Vec::new(),
+        })
+    }
+
+    pub fn try_clone(&self) -> io::Result<Self> {
+        Ok(Self {
+            tcp: self.tcp.try_clone()?,
+            tls: self.tls.clone(),
+            ciphertext: Vec::new(),
+        })
+    }
+
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        self.tcp.shutdown(how)
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.tcp.set_read_timeout(timeout)
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.tcp.peer_addr()
+    }
+
+    // Whether the peer presented a certificate we trust.
+    pub fn peer_certified(&self) -> bool {
+        self.tls
+            .as_ref()
+            .is_some_and(|tls| tls.state.lock().unwrap().conn.peer_certificates().is_some())
+    }
+}
+
+// The socket is only read and written with the state unlocked, so a reader
+// waiting for data never holds up a writer or the other way round.
+impl Read for Connection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let Some(tls) = &self.tls else {
+            return self.tcp.read(buf);
+        };
+        let ciphertext = &mut self.ciphertext;
+        ciphertext.resize(READ_SIZE, 0);
+        loop {
+            {
+                let mut state = tls.state.lock().unwrap();
+                match state.conn.reader().read(buf) {
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                    done => return done,
+                }
+                if !state.pending.is_empty() || state.eof {
+                    state.decrypt()?;
+                    // Anything rustls has to answer goes out with the next
+                    // write if a writer is busy now
+                    if let Ok(_sending) = tls.sending.try_lock() {
+                        state.flush(&self.tcp)?;
+                    }
+                    continue;
+                }
+            }
+            let n = self.tcp.read(ciphertext)?;
+            let mut state = tls.state.lock().unwrap();
+            state.eof = n == 0;
+            state.pending.extend_from_slice(&ciphertext[..n]);
+        }
+    }
+}
+
+impl Write for Connection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let Some(tls) = &self.tls else {
+            return self.tcp.write(buf);
+        };
+        let _sending = tls.sending.lock().unwrap();
+        let (n, records) = {
+            let mut state = tls.state.lock().unwrap();
+            let n = state.conn.writer().write(buf)?;
+            let mut records = Vec::new();
+            while state.conn.wants_write() {
+                state.conn.write_tls(&mut records)?;
+            }
+            (n, records)
+        };
+        self.tcp.write_all(&records)?;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.tcp.flush()
+    }
+}
+
+impl TlsState {
+    // Hand the ciphertext read so far to rustls. Called only once the
+    // plaintext it had is used up, so its buffer can't overflow.
+    fn decrypt(&mut self) -> io::Result<()> {
+        let mut rest = &self.pending[..];
+        let used = self.conn.read_tls(&mut rest)?;
+        if used == 0 {
+            // The peer closed the session; nothing after that counts
+            self.pending.clear();
+        } else {
+            self.pending.drain(..used);
+        }
+        self.conn.process_new_packets().map_err(invalid)?;
+        Ok(())
+    }
+
+    fn flush(&mut self, mut tcp: &TcpStream) -> io::Result<()> {
+        while self.conn.wants_write() {
+            self.conn.write_tls(&mut tcp)?;
+        }
+        Ok(())
+    }
+}
+
+fn is_same_machine(peer: SocketAddr, local: SocketAddr) -> bool {
+    peer.ip().is_loopback() || peer.ip() == local.ip()
+}
+
+fn load_certs(path: &Path) -> io::Result<Vec<CertificateDer<'static>>> {
+    let certs = CertificateDer::pem_reader_iter(BufReader::new(open(path)?))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| pem_error(path, e))?;
+    if certs.is_empty() {
+        return Err(pem_error(path, "no certificate in the file"));
+    }
+    Ok(certs)
+}
+
+fn load_key(path: &Path) -> io::Result<PrivateKeyDer<'static>> {
+    PrivateKeyDer::from_pem_reader(BufReader::new(open(path)?)).map_err(|e| pem_error(path, e))
+}
+
+fn load_roots(path: &Path) -> io::Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(path)? {
+        roots.add(cert).map_err(|e| pem_error(path, e))?;
+    }
+    Ok(roots)
+}
+
+fn open(path: &Path) -> io::Result<File> {
+    File::open(path).map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))
+}
+
+fn pem_error(path: &Path, e: impl std::fmt::Display) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), e))
+}
+
+fn invalid(e: impl std::fmt::Display) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::path::PathBuf;
+    use std::thread;
+
+    fn testdata(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("src/utils/testdata").join(name)
+    }
+
+    fn server(worker_ca: bool) -> ServerTls {
+        let ca = testdata("ca.pem");
+        server_tls(&testdata("server.pem"), &testdata("server.key"), worker_ca.then_some(ca.as_path())).unwrap()
+    }
+
+    fn client(with_certificate: bool) -> Arc<ClientConfig> {
+        let (cert, key) = (testdata("worker.pem"), testdata("worker.key"));
+        client_tls(&testdata("ca.pem"), with_certificate.then_some((cert.as_path(), key.as_path()))).unwrap()
+    }
+
+    // Connect a client to a server over TLS; returns both ends.
+    fn pair(server: ServerTls, client: Arc<ClientConfig>, host: &str) -> io::Result<(Connection, Connection)> {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = format!("{}:{}", host, listener.local_addr().unwrap().port());
+        let accepting = thread::spawn(move || {
+            let (tcp, _) = listener.accept()?;
+            Connection::server_handshake(tcp, &server.config)
+        });
+        let client = Connection::client_handshake(TcpStream::connect(&addr)?, &addr, &client);
+        let server = accepting.join().unwrap();
+        Ok((client?, server?))
+    }
+
+    #[test]
+    fn both_ends_can_send_at_once() {
+        let (client, server) = pair(server(false), client(false), "127.0.0.1").unwrap();
+        assert!(client.tls.is_some() && !server.peer_certified());
+
+        // Both sides send more than the socket buffers hold before reading
+        let body: Vec<u8> = (0..4 << 20).map(|i| (i % 251) as u8).collect();
+        let exchange = |conn: Connection| {
+            let body = body.clone();
+            thread::spawn(move || {
+                let mut writer = conn.try_clone().unwrap();
+                let sending = thread::spawn(move || writer.write_all(&body));
+                let mut reader = conn;
+                let mut received = vec![0u8; 4 << 20];
+                reader.read_exact(&mut received).unwrap();
+                sending.join().unwrap().unwrap();
+                received
+            })
+        };
+        let (from_server, from_client) = (exchange(client), exchange(server));
+        assert!(from_server.join().unwrap() == body);
+        assert!(from_client.join().unwrap() == body);
+    }
+
+    #[test]
+    fn workers_prove_themselves_with_a_certificate() {
+        let (mut client, mut server) = pair(server(true), client(true), "127.0.0.1").unwrap();
+        assert!(server.peer_certified());
+        client.write_all(b"hello").unwrap();
+        let mut received = [0u8; 5];
+        server.read_exact(&mut received).unwrap();
+        assert_eq!(&received, b"hello");
+
+        // Clients needn't have one
+        let (_, server) = pair(self::server(true), self::client(false), "127.0.0.1").unwrap();
+        assert!(!server.peer_certified());
+    }
+
+    #[test]
+    fn stalled_handshakes_time_out() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut peer = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (tcp, _) = listener.accept().unwrap();
+        let config = server(false).config;
+
+        // Says nothing at all...
+        let limit = Duration::from_millis(100);
+        let err = Connection::within(limit, tcp.try_clone().unwrap(), |tcp| Connection::server_handshake(tcp, &config));
+        assert!(matches!(err.err().unwrap().kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut));
+
+        // ...or starts a handshake and never finishes it
+        peer.write_all(&[TLS_HANDSHAKE]).unwrap();
+        let err = Connection::within(limit, tcp, |tcp| Connection::server_handshake(tcp, &config));
+        assert!(matches!(err.err().unwrap().kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut));
+    }
+
+    #[test]
+    fn servers_must_match_their_certificate() {
+        // The test certificate is for 127.0.0.1 only
+        let err = pair(server(false), client(false), "localhost").err().unwrap();
+        assert!(err.to_string().contains("certificate"), "{}", err);
+    }
+
+    #[test]
+    fn unreadable_files_are_named_in_errors() {
+        let missing = testdata("missing.pem");
+        let err = client_tls(&missing, None).err().unwrap();
+        assert!(err.to_string().contains("missing.pem"));
+
+        let err = server_tls(&testdata("server.pem"), &testdata("server.pem"), None).err().unwrap();
+        assert!(err.to_string().contains("server.pem"), "{}", err);
+    }
+
+    #[test]
+    fn only_our_own_secret_makes_a_local_worker() {
+        assert!(!is_local_worker(""));
+        let ours = local_worker_secret();
+        assert_eq!(ours.len(), 64);
+        assert_eq!(local_worker_secret(), ours);
+        assert!(is_local_worker(ours));
+        assert!(!is_local_worker(&ours[1..]));
+    }
+
+    #[test]
+    fn plain_connections_only_from_this_machine() {
+        let local: SocketAddr = "192.168.1.5:9000".parse().unwrap();
+        assert!(is_same_machine("127.0.0.1:5000".parse().unwrap(), local));
+        assert!(is_same_machine("192.168.1.5:5000".parse().unwrap(), local));
+        assert!(!is_same_machine("192.168.1.6:5000".parse().unwrap(), local));
+    }
+}
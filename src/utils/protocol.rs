@@ -1,17 +1,39 @@
 use std::convert::TryFrom;
-use std::io::{self, Read};
-use std::net::TcpStream;
+use std::ffi::OsString;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Cursor, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
 
-use crate::config::HEADER_SIZE;
+use clap::ValueEnum;
+use sha2::{Digest as _, Sha256};
+
+use crate::config::{CHUNK_SIZE, HEADER_SIZE, MAX_BODY_IN_MEMORY, MAX_FRAME_SIZE};
+
+use super::compression::Compression;
+use super::handshake::{DBS_VERSION, PROTOCOL_VERSION};
+use super::net::Connection;
 
 #[derive(Debug, PartialEq, Copy, Clone)]
 #[repr(u8)]
 pub enum OpCode {
-    Hello = 0x01,       // Worker -> Controller: "Ready"
+    Hello = 0x01,       // Worker -> Controller: "Ready, here's what I can do"
     TaskDef = 0x02,     // Controller -> Worker: "Compile this file path"
     TaskResult = 0x03,  // Worker -> Controller: "Success/Fail + Output"
     SubmitFile = 0x04,  // Client -> Server: "Here's a .c file to compile"
     FileResult = 0x05,  // Server -> Client: "Here's your .o file"
+    Welcome = 0x06,     // Controller -> Worker: "Accepted" or "Rejected: reason"
+    Heartbeat = 0x07,   // Both ways: "Still here" while busy or idle
+    SubmitBatch = 0x08, // Client -> Server: "These files will follow on this connection"
+    BatchFile = 0x09,   // Client -> Server: "File #n of the batch"
+    BatchResult = 0x0A, // Server -> Client: "Result for file #n"
+    BatchEnd = 0x0B,    // Both ways: "No more files" / "No more results"
+    BodyBegin = 0x0C,   // Both ways: "A file body of n bytes follows"
+    BodyChunk = 0x0D,   // Both ways: "Next piece of the body"
+    BodyEnd = 0x0E,     // Both ways: "That was the whole body"
+    Resume = 0x0F,      // Client -> Server: "Send the rest of that object"
     Shutdown = 0xFF,    // Controller -> Worker: "Exit"
 }
 
@@ -24,41 +46,1329 @@ impl TryFrom<u8> for OpCode {
             0x03 => Ok(OpCode::TaskResult),
             0x04 => Ok(OpCode::SubmitFile),
             0x05 => Ok(OpCode::FileResult),
+            0x06 => Ok(OpCode::Welcome),
+            0x07 => Ok(OpCode::Heartbeat),
+            0x08 => Ok(OpCode::SubmitBatch),
+            0x09 => Ok(OpCode::BatchFile),
+            0x0A => Ok(OpCode::BatchResult),
+            0x0B => Ok(OpCode::BatchEnd),
+            0x0C => Ok(OpCode::BodyBegin),
+            0x0D => Ok(OpCode::BodyChunk),
+            0x0E => Ok(OpCode::BodyEnd),
+            0x0F => Ok(OpCode::Resume),
             0xFF => Ok(OpCode::Shutdown),
             _ => Err(()),
         }
     }
 }
 
+// Outcome byte at the start of TaskResult and FileResult payloads.
+#[derive(Debug, PartialEq, Copy, Clone)]
+#[repr(u8)]
+pub enum Status {
+    Failed = 0,
+    Succeeded = 1,
+    TimedOut = 2,
+    // The server refused the job without trying to compile it
+    Rejected = 3,
+    // What was sent failed its integrity check; sending it again may work
+    Corrupted = 4,
+}
+
+impl From<u8> for Status {
+    fn from(v: u8) -> Self {
+        match v {
+            1 => Status::Succeeded,
+            2 => Status::TimedOut,
+            3 => Status::Rejected,
+            4 => Status::Corrupted,
+            _ => Status::Failed,
+        }
+    }
+}
+
+// How urgently a submitted job should be scheduled.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Copy, Clone, Default, ValueEnum)]
+#[repr(u8)]
+pub enum Priority {
+    Low = 0,
+    #[default]
+    Normal = 1,
+    High = 2,
+}
+
+impl From<u8> for Priority {
+    fn from(v: u8) -> Self {
+        match v {
+            0 => Priority::Low,
+            2 => Priority::High,
+            _ => Priority::Normal,
+        }
+    }
+}
+
 pub struct Message {
     pub op: OpCode,
     pub payload: Vec<u8>,
 }
 
+// Frame: [1 byte op][1 byte flags][4 bytes payload len][payload]
+// With FLAG_ZSTD the payload is zstd-compressed and the length is that of
+// the compressed payload.
+const FLAG_ZSTD: u8 = 0x01;
+
 impl Message {
     pub fn new(op: OpCode, payload: Vec<u8>) -> Self {
         Self { op, payload }
     }
 
     pub fn serialize(&self) -> Vec<u8> {
-        let mut buf = Vec::new();
-        buf.push(self.op as u8);
-        buf.extend_from_slice(&(self.payload.len() as u32).to_be_bytes());
-        buf.extend_from_slice(&self.payload);
-        buf
+        frame(self.op, 0, &self.payload)
+    }
+
+    // Compressed when that makes the frame smaller.
+    fn serialize_with(&self, compression: Compression) -> Vec<u8> {
+        match compression.compress(&self.payload) {
+            Some(compressed) => frame(self.op, FLAG_ZSTD, &compressed),
+            None => self.serialize(),
+        }
+    }
+
+    // Frames over MAX_FRAME_SIZE, before or after decompression, are
+    // refused before anything is allocated for them; file bodies travel in
+    // chunks instead.
+    pub fn read(stream: &mut impl FrameRead) -> io::Result<Message> {
+        stream.read_frame()
     }
 
-    pub fn read(stream: &mut TcpStream) -> io::Result<Message> {
+    // Also returns the size of the payload as it was on the wire.
+    fn read_raw(stream: &mut impl Read) -> io::Result<(Message, usize)> {
         let mut header = [0u8; HEADER_SIZE];
         stream.read_exact(&mut header)?;
 
         let op = OpCode::try_from(header[0])
             .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Invalid Op"))?;
-        let len = u32::from_be_bytes(header[1..5].try_into().unwrap()) as usize;
+        let compression = match header[1] {
+            0 => Compression::None,
+            FLAG_ZSTD => Compression::Zstd,
+            flags => return Err(malformed(format!("unknown frame flags {:#04x}", flags))),
+        };
+        let len = u32::from_be_bytes(header[2..6].try_into().unwrap()) as usize;
+        if len > MAX_FRAME_SIZE {
+            return Err(malformed(format!(
+                "{:?} frame of {} bytes exceeds the {} byte limit",
+                op, len, MAX_FRAME_SIZE
+            )));
+        }
 
         let mut payload = vec![0u8; len];
         stream.read_exact(&mut payload)?;
+        if compression != Compression::None {
+            payload = compression
+                .decompress(&payload, MAX_FRAME_SIZE)
+                .map_err(|e| malformed(format!("{:?} frame does not decompress: {}", op, e)))?;
+        }
+
+        Ok((Message { op, payload }, len))
+    }
+}
+
+fn frame(op: OpCode, flags: u8, payload: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(HEADER_SIZE + payload.len());
+    buf.push(op as u8);
+    buf.push(flags);
+    buf.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    buf.extend_from_slice(payload);
+    buf
+}
+
+// Where messages are read from and written to: any stream as it is, or a
+// Link that compresses and counts.
+pub trait FrameRead {
+    fn read_frame(&mut self) -> io::Result<Message>;
+}
+
+pub trait FrameWrite {
+    fn write_frame(&mut self, msg: &Message) -> io::Result<()>;
+}
+
+impl<R: Read> FrameRead for R {
+    fn read_frame(&mut self) -> io::Result<Message> {
+        Ok(Message::read_raw(self)?.0)
+    }
+}
+
+impl<W: Write> FrameWrite for W {
+    fn write_frame(&mut self, msg: &Message) -> io::Result<()> {
+        self.write_all(&msg.serialize())
+    }
+}
+
+// Payload bytes a connection carried, as the messages had them and as they
+// went over the wire. Shared by every clone of the connection.
+#[derive(Default)]
+pub struct WireStats {
+    sent_raw: AtomicU64,
+    sent_wire: AtomicU64,
+    received_raw: AtomicU64,
+    received_wire: AtomicU64,
+}
+
+impl WireStats {
+    pub fn summary(&self) -> String {
+        let kib = |bytes: &AtomicU64| bytes.load(Ordering::Relaxed).div_ceil(1024);
+        format!(
+            "sent {} KiB as {} KiB, received {} KiB as {} KiB",
+            kib(&self.sent_raw),
+            kib(&self.sent_wire),
+            kib(&self.received_raw),
+            kib(&self.received_wire)
+        )
+    }
+}
+
+// A connection whose outgoing frames are compressed as negotiated in the
+// handshake. Incoming frames say themselves whether they are compressed.
+pub struct Link<S> {
+    stream: S,
+    compression: Compression,
+    stats: Arc<WireStats>,
+}
+
+impl<S> Link<S> {
+    pub fn new(stream: S, compression: Compression) -> Self {
+        Self {
+            stream,
+            compression,
+            stats: Arc::new(WireStats::default()),
+        }
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+
+    pub fn stats(&self) -> &WireStats {
+        &self.stats
+    }
+}
+
+impl Link<Connection> {
+    // Another handle on the same connection, counting into the same stats.
+    pub fn try_clone(&self) -> io::Result<Self> {
+        Ok(Self {
+            stream: self.stream.try_clone()?,
+            compression: self.compression,
+            stats: Arc::clone(&self.stats),
+        })
+    }
+}
+
+impl<S: Read> FrameRead for Link<S> {
+    fn read_frame(&mut self) -> io::Result<Message> {
+        let (msg, wire_len) = Message::read_raw(&mut self.stream)?;
+        self.stats.received_raw.fetch_add(msg.payload.len() as u64, Ordering::Relaxed);
+        self.stats.received_wire.fetch_add(wire_len as u64, Ordering::Relaxed);
+        Ok(msg)
+    }
+}
+
+impl<S: Write> FrameWrite for Link<S> {
+    fn write_frame(&mut self, msg: &Message) -> io::Result<()> {
+        let frame = msg.serialize_with(self.compression);
+        self.stream.write_all(&frame)?;
+        self.stats.sent_raw.fetch_add(msg.payload.len() as u64, Ordering::Relaxed);
+        self.stats.sent_wire.fetch_add((frame.len() - HEADER_SIZE) as u64, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+// Payload layouts
+//
+// Every payload is a sequence of fields: integers are big-endian, strings
+// and byte blocks are [4 bytes len][bytes], lists are [4 bytes count]{item}.
+// A trailing "rest" field runs to the end of the payload. Each message below
+// is a struct that knows its OpCode and how to write and read itself, so a
+// new field is added in one place.
+
+fn malformed(reason: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason.into())
+}
+
+// Builds a payload field by field.
+#[derive(Default)]
+pub struct Encoder {
+    buf: Vec<u8>,
+}
+
+impl Encoder {
+    pub fn u8(&mut self, value: u8) -> &mut Self {
+        self.buf.push(value);
+        self
+    }
+
+    pub fn u16(&mut self, value: u16) -> &mut Self {
+        self.buf.extend_from_slice(&value.to_be_bytes());
+        self
+    }
+
+    pub fn u32(&mut self, value: u32) -> &mut Self {
+        self.buf.extend_from_slice(&value.to_be_bytes());
+        self
+    }
+
+    pub fn u64(&mut self, value: u64) -> &mut Self {
+        self.buf.extend_from_slice(&value.to_be_bytes());
+        self
+    }
+
+    pub fn bytes(&mut self, value: &[u8]) -> &mut Self {
+        self.u32(value.len() as u32);
+        self.buf.extend_from_slice(value);
+        self
+    }
+
+    pub fn string(&mut self, value: &str) -> &mut Self {
+        self.bytes(value.as_bytes())
+    }
+
+    pub fn strings(&mut self, values: &[String]) -> &mut Self {
+        self.u32(values.len() as u32);
+        for value in values {
+            self.string(value);
+        }
+        self
+    }
+
+    // Files as [4 bytes count]{[path][contents]}
+    pub fn files(&mut self, files: &[(String, Vec<u8>)]) -> &mut Self {
+        self.u32(files.len() as u32);
+        for (path, contents) in files {
+            self.string(path).bytes(contents);
+        }
+        self
+    }
+
+    // Unprefixed; must be the last field.
+    pub fn rest(&mut self, value: &[u8]) -> &mut Self {
+        self.buf.extend_from_slice(value);
+        self
+    }
+
+    pub fn into_inner(self) -> Vec<u8> {
+        self.buf
+    }
+}
+
+// Reads a payload field by field. Every read fails with InvalidData, naming
+// the field, when the payload is too short for it.
+pub struct Decoder<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Decoder<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        Self { buf }
+    }
+
+    fn take(&mut self, len: usize, field: &str) -> io::Result<&'a [u8]> {
+        if self.buf.len() < len {
+            return Err(malformed(format!("truncated at {}", field)));
+        }
+        let (taken, rest) = self.buf.split_at(len);
+        self.buf = rest;
+        Ok(taken)
+    }
+
+    pub fn u8(&mut self, field: &str) -> io::Result<u8> {
+        Ok(self.take(1, field)?[0])
+    }
+
+    pub fn u16(&mut self, field: &str) -> io::Result<u16> {
+        Ok(u16::from_be_bytes(self.take(2, field)?.try_into().unwrap()))
+    }
+
+    pub fn u32(&mut self, field: &str) -> io::Result<u32> {
+        Ok(u32::from_be_bytes(self.take(4, field)?.try_into().unwrap()))
+    }
+
+    pub fn u64(&mut self, field: &str) -> io::Result<u64> {
+        Ok(u64::from_be_bytes(self.take(8, field)?.try_into().unwrap()))
+    }
+
+    pub fn bytes(&mut self, field: &str) -> io::Result<&'a [u8]> {
+        let len = self.u32(field)? as usize;
+        self.take(len, field)
+    }
+
+    pub fn string(&mut self, field: &str) -> io::Result<String> {
+        Ok(String::from_utf8_lossy(self.bytes(field)?).to_string())
+    }
+
+    pub fn strings(&mut self, field: &str) -> io::Result<Vec<String>> {
+        let count = self.u32(field)?;
+        (0..count).map(|_| self.string(field)).collect()
+    }
+
+    pub fn files(&mut self, field: &str) -> io::Result<Vec<(String, Vec<u8>)>> {
+        let count = self.u32(field)?;
+        (0..count)
+            .map(|_| Ok((self.string(field)?, self.bytes(field)?.to_vec())))
+            .collect()
+    }
+
+    pub fn rest(&mut self) -> &'a [u8] {
+        std::mem::take(&mut self.buf)
+    }
+
+    // Fails if anything is left over.
+    pub fn finish(&self) -> io::Result<()> {
+        if self.buf.is_empty() {
+            Ok(())
+        } else {
+            Err(malformed(format!("{} unexpected trailing bytes", self.buf.len())))
+        }
+    }
+
+    // The protocol version that starts Hello, SubmitFile and SubmitBatch.
+    // Anything after it may be laid out differently in other releases, so a
+    // mismatch is reported as Unsupported with a reason for the peer.
+    pub fn version(&mut self) -> io::Result<u16> {
+        let version = self.u16("protocol version")?;
+        if version != PROTOCOL_VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!(
+                    "protocol version {} is not supported (expected {}, dbs {})",
+                    version, PROTOCOL_VERSION, DBS_VERSION
+                ),
+            ));
+        }
+        Ok(version)
+    }
+}
+
+// A typed message body.
+pub trait Payload: Sized {
+    const OP: OpCode;
+
+    fn encode(&self, out: &mut Encoder);
+    fn decode(input: &mut Decoder) -> io::Result<Self>;
+
+    fn to_message(&self) -> Message {
+        let mut out = Encoder::default();
+        self.encode(&mut out);
+        Message::new(Self::OP, out.into_inner())
+    }
+
+    // Fails on the wrong OpCode, a short payload or trailing bytes. A
+    // version mismatch keeps its own readable error.
+    fn from_message(msg: &Message) -> io::Result<Self> {
+        if msg.op != Self::OP {
+            return Err(malformed(format!("expected {:?}, got {:?}", Self::OP, msg.op)));
+        }
+        let mut input = Decoder::new(&msg.payload);
+        let value = Self::decode(&mut input).and_then(|value| input.finish().map(|_| value));
+        value.map_err(|e| match e.kind() {
+            io::ErrorKind::Unsupported => e,
+            _ => malformed(format!("malformed {:?}: {}", Self::OP, e)),
+        })
+    }
+
+    // Refuses to send a frame the peer would refuse to read.
+    fn send(&self, stream: &mut impl FrameWrite) -> io::Result<()> {
+        let msg = self.to_message();
+        if msg.payload.len() > MAX_FRAME_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "{:?} of {} bytes exceeds the {} byte frame limit",
+                    Self::OP,
+                    msg.payload.len(),
+                    MAX_FRAME_SIZE
+                ),
+            ));
+        }
+        stream.write_frame(&msg)
+    }
+}
+
+// Everything needed to compile a source file, as a client sends it. The
+// source itself follows as a body.
+// [filename][compiler][args][origin][headers]
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Submission {
+    pub filename: String,
+    // Empty for the server's default
+    pub compiler: String,
+    pub args: Vec<String>,
+    // For bundled sources: the source's absolute path on the client, with
+    // its headers keyed the same way. Empty otherwise.
+    pub origin: String,
+    pub headers: Vec<(String, Vec<u8>)>,
+}
+
+impl Submission {
+    fn encode(&self, out: &mut Encoder) {
+        out.string(&self.filename)
+            .string(&self.compiler)
+            .strings(&self.args)
+            .string(&self.origin)
+            .files(&self.headers);
+    }
+
+    fn decode(input: &mut Decoder) -> io::Result<Self> {
+        Ok(Self {
+            filename: input.string("filename")?,
+            compiler: input.string("compiler")?,
+            args: input.strings("arguments")?,
+            origin: input.string("origin")?,
+            headers: input.files("headers")?,
+        })
+    }
+}
+
+// [2 bytes protocol version][1 byte priority][submission], then the source
+#[derive(Debug, Clone, PartialEq)]
+pub struct SubmitFile {
+    pub priority: Priority,
+    pub submission: Submission,
+}
+
+impl Payload for SubmitFile {
+    const OP: OpCode = OpCode::SubmitFile;
+
+    fn encode(&self, out: &mut Encoder) {
+        out.u16(PROTOCOL_VERSION).u8(self.priority as u8);
+        self.submission.encode(out);
+    }
+
+    fn decode(input: &mut Decoder) -> io::Result<Self> {
+        input.version()?;
+        Ok(Self {
+            priority: Priority::from(input.u8("priority")?),
+            submission: Submission::decode(input)?,
+        })
+    }
+}
+
+// [1 byte status][name], followed by the object file or error message as a
+// body. The name is the object's on success and the source's otherwise.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileResult {
+    pub status: Status,
+    pub name: String,
+}
+
+impl Payload for FileResult {
+    const OP: OpCode = OpCode::FileResult;
+
+    fn encode(&self, out: &mut Encoder) {
+        out.u8(self.status as u8).string(&self.name);
+    }
+
+    fn decode(input: &mut Decoder) -> io::Result<Self> {
+        Ok(Self {
+            status: Status::from(input.u8("status")?),
+            name: input.string("name")?,
+        })
+    }
+}
+
+// [2 bytes protocol version][1 byte priority][names of the files to follow]
+#[derive(Debug, Clone, PartialEq)]
+pub struct SubmitBatch {
+    pub priority: Priority,
+    pub names: Vec<String>,
+}
+
+impl Payload for SubmitBatch {
+    const OP: OpCode = OpCode::SubmitBatch;
+
+    fn encode(&self, out: &mut Encoder) {
+        out.u16(PROTOCOL_VERSION).u8(self.priority as u8).strings(&self.names);
+    }
+
+    fn decode(input: &mut Decoder) -> io::Result<Self> {
+        input.version()?;
+        Ok(Self {
+            priority: Priority::from(input.u8("priority")?),
+            names: input.strings("file names")?,
+        })
+    }
+}
+
+// [4 bytes index into the batch][submission], then the source
+#[derive(Debug, Clone, PartialEq)]
+pub struct BatchFile {
+    pub index: u32,
+    pub submission: Submission,
+}
+
+impl Payload for BatchFile {
+    const OP: OpCode = OpCode::BatchFile;
+
+    fn encode(&self, out: &mut Encoder) {
+        out.u32(self.index);
+        self.submission.encode(out);
+    }
+
+    fn decode(input: &mut Decoder) -> io::Result<Self> {
+        Ok(Self {
+            index: input.u32("index")?,
+            submission: Submission::decode(input)?,
+        })
+    }
+}
+
+// [4 bytes index into the batch][FileResult payload], then its body
+#[derive(Debug, Clone, PartialEq)]
+pub struct BatchResult {
+    pub index: u32,
+    pub result: FileResult,
+}
+
+impl Payload for BatchResult {
+    const OP: OpCode = OpCode::BatchResult;
+
+    fn encode(&self, out: &mut Encoder) {
+        out.u32(self.index);
+        self.result.encode(out);
+    }
+
+    fn decode(input: &mut Decoder) -> io::Result<Self> {
+        Ok(Self {
+            index: input.u32("index")?,
+            result: FileResult::decode(input)?,
+        })
+    }
+}
+
+// [rest: why the server refused the batch, empty if it didn't]
+#[derive(Debug, Clone, PartialEq)]
+pub struct BatchEnd {
+    pub error: Option<String>,
+}
+
+impl Payload for BatchEnd {
+    const OP: OpCode = OpCode::BatchEnd;
+
+    fn encode(&self, out: &mut Encoder) {
+        out.rest(self.error.as_deref().unwrap_or("").as_bytes());
+    }
+
+    fn decode(input: &mut Decoder) -> io::Result<Self> {
+        let error = input.rest();
+        Ok(Self {
+            error: (!error.is_empty()).then(|| String::from_utf8_lossy(error).to_string()),
+        })
+    }
+}
+
+// [filename][compiler][4 bytes exec timeout secs][args][origin][headers]
+// [1 byte deps], then the source as a body
+#[derive(Debug, Clone, PartialEq)]
+pub struct TaskDef {
+    // Bare file name; the worker places it in its scratch directory
+    pub filename: String,
+    pub compiler: String,
+    pub timeout_secs: u32,
+    pub args: Vec<String>,
+    pub origin: String,
+    pub headers: Vec<(String, Vec<u8>)>,
+    // List the files the compile read in the result
+    pub deps: bool,
+}
+
+impl Payload for TaskDef {
+    const OP: OpCode = OpCode::TaskDef;
+
+    fn encode(&self, out: &mut Encoder) {
+        out.string(&self.filename)
+            .string(&self.compiler)
+            .u32(self.timeout_secs)
+            .strings(&self.args)
+            .string(&self.origin)
+            .files(&self.headers)
+            .u8(self.deps as u8);
+    }
+
+    fn decode(input: &mut Decoder) -> io::Result<Self> {
+        Ok(Self {
+            filename: input.string("filename")?,
+            compiler: input.string("compiler")?,
+            timeout_secs: input.u32("exec timeout")?,
+            args: input.strings("arguments")?,
+            origin: input.string("origin")?,
+            headers: input.files("headers")?,
+            deps: input.u8("deps")? != 0,
+        })
+    }
+}
+
+// [1 byte status][stdout][stderr][deps], then the object file as a body
+// (empty unless the status is Succeeded)
+#[derive(Debug, Clone, PartialEq)]
+pub struct TaskResult {
+    pub status: Status,
+    pub stdout: String,
+    pub stderr: String,
+    // Headers the compile read, as paths on the controller's machine, when
+    // the TaskDef asked for them
+    pub deps: Vec<String>,
+}
+
+impl TaskResult {
+    // A result with nothing but an error message.
+    pub fn failed(status: Status, reason: &str) -> Self {
+        Self {
+            status,
+            stdout: String::new(),
+            stderr: reason.to_string(),
+            deps: Vec::new(),
+        }
+    }
+}
+
+impl Payload for TaskResult {
+    const OP: OpCode = OpCode::TaskResult;
+
+    fn encode(&self, out: &mut Encoder) {
+        out.u8(self.status as u8)
+            .string(&self.stdout)
+            .string(&self.stderr)
+            .strings(&self.deps);
+    }
+
+    fn decode(input: &mut Decoder) -> io::Result<Self> {
+        Ok(Self {
+            status: Status::from(input.u8("status")?),
+            stdout: input.string("stdout")?,
+            stderr: input.string("stderr")?,
+            deps: input.strings("deps")?,
+        })
+    }
+}
+
+// Accepted: [1][compression name]. Rejected: [0][rest: reason]
+// Everything after an accepting Welcome is compressed as it names.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Welcome {
+    pub rejection: Option<String>,
+    pub compression: Compression,
+}
+
+impl Payload for Welcome {
+    const OP: OpCode = OpCode::Welcome;
+
+    fn encode(&self, out: &mut Encoder) {
+        match &self.rejection {
+            None => out.u8(1).string(self.compression.name()),
+            Some(reason) => out.u8(0).rest(reason.as_bytes()),
+        };
+    }
+
+    fn decode(input: &mut Decoder) -> io::Result<Self> {
+        if input.u8("accepted")? != 1 {
+            let reason = String::from_utf8_lossy(input.rest()).to_string();
+            return Ok(Self {
+                rejection: Some(reason),
+                compression: Compression::None,
+            });
+        }
+        let name = input.string("compression")?;
+        let compression = Compression::from_name(&name)
+            .ok_or_else(|| malformed(format!("unknown compression '{}'", name)))?;
+        Ok(Self {
+            rejection: None,
+            compression,
+        })
+    }
+}
+
+// Empty payload
+#[derive(Debug, Clone, PartialEq)]
+pub struct Heartbeat;
+
+impl Payload for Heartbeat {
+    const OP: OpCode = OpCode::Heartbeat;
+
+    fn encode(&self, _: &mut Encoder) {}
+
+    fn decode(_: &mut Decoder) -> io::Result<Self> {
+        Ok(Self)
+    }
+}
+
+// Empty payload
+#[derive(Debug, Clone, PartialEq)]
+pub struct Shutdown;
+
+impl Payload for Shutdown {
+    const OP: OpCode = OpCode::Shutdown;
+
+    fn encode(&self, _: &mut Encoder) {}
+
+    fn decode(_: &mut Decoder) -> io::Result<Self> {
+        Ok(Self)
+    }
+}
+
+// [2 bytes protocol version][transfer id][8 bytes offset]
+// Opens a connection of its own; answered with a FileResult whose body
+// starts at `offset`, or that says why it can't.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Resume {
+    pub transfer: String,
+    pub offset: u64,
+}
+
+impl Payload for Resume {
+    const OP: OpCode = OpCode::Resume;
+
+    fn encode(&self, out: &mut Encoder) {
+        out.u16(PROTOCOL_VERSION).string(&self.transfer).u64(self.offset);
+    }
+
+    fn decode(input: &mut Decoder) -> io::Result<Self> {
+        input.version()?;
+        Ok(Self {
+            transfer: input.string("transfer id")?,
+            offset: input.u64("offset")?,
+        })
+    }
+}
+
+// File bodies
+//
+// Sources, objects and logs can be larger than a frame, so they follow the
+// message they belong to as BodyBegin, any number of BodyChunk frames of at
+// most CHUNK_SIZE bytes, and BodyEnd. Nothing else is sent on the
+// connection in between. A body with a transfer id can be fetched again
+// from its offset with Resume if the connection breaks.
+
+// [8 bytes total size][8 bytes offset of the first chunk][transfer id]
+// [SHA-256 of the whole body]
+#[derive(Debug, Clone, PartialEq)]
+pub struct BodyBegin {
+    pub size: u64,
+    pub offset: u64,
+    // Empty when the sender can't resume the body
+    pub transfer: String,
+    // Of all `size` bytes, so a resumed body is checked as a whole
+    pub digest: Digest,
+}
+
+impl Payload for BodyBegin {
+    const OP: OpCode = OpCode::BodyBegin;
+
+    fn encode(&self, out: &mut Encoder) {
+        out.u64(self.size).u64(self.offset).string(&self.transfer).bytes(&self.digest);
+    }
+
+    fn decode(input: &mut Decoder) -> io::Result<Self> {
+        Ok(Self {
+            size: input.u64("size")?,
+            offset: input.u64("offset")?,
+            transfer: input.string("transfer id")?,
+            digest: input
+                .bytes("digest")?
+                .try_into()
+                .map_err(|_| malformed("digest is not 32 bytes".to_string()))?,
+        })
+    }
+}
+
+// [rest: bytes]
+#[derive(Debug, Clone, PartialEq)]
+pub struct BodyChunk {
+    pub data: Vec<u8>,
+}
+
+impl Payload for BodyChunk {
+    const OP: OpCode = OpCode::BodyChunk;
+
+    fn encode(&self, out: &mut Encoder) {
+        out.rest(&self.data);
+    }
+
+    fn decode(input: &mut Decoder) -> io::Result<Self> {
+        Ok(Self {
+            data: input.rest().to_vec(),
+        })
+    }
+}
+
+// Empty payload
+#[derive(Debug, Clone, PartialEq)]
+pub struct BodyEnd;
+
+impl Payload for BodyEnd {
+    const OP: OpCode = OpCode::BodyEnd;
+
+    fn encode(&self, _: &mut Encoder) {}
+
+    fn decode(_: &mut Decoder) -> io::Result<Self> {
+        Ok(Self)
+    }
+}
+
+// Send the bytes of `body` from `offset` up to `size`. The whole body is
+// hashed first, so the receiver can check it however it was split.
+pub fn send_body(
+    stream: &mut impl FrameWrite,
+    body: &mut (impl Read + Seek),
+    size: u64,
+    offset: u64,
+    transfer: &str,
+) -> io::Result<()> {
+    body.seek(SeekFrom::Start(0))?;
+    let digest = digest_reader(&mut body.take(size))?;
+    body.seek(SeekFrom::Start(offset))?;
+    BodyBegin {
+        size,
+        offset,
+        transfer: transfer.to_string(),
+        digest,
+    }
+    .send(stream)?;
+
+    let mut buf = vec![0u8; CHUNK_SIZE];
+    let mut sent = offset;
+    while sent < size {
+        let want = (size - sent).min(CHUNK_SIZE as u64) as usize;
+        let n = body.read(&mut buf[..want])?;
+        if n == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("body ended after {} of {} bytes", sent, size),
+            ));
+        }
+        BodyChunk { data: buf[..n].to_vec() }.send(stream)?;
+        sent += n as u64;
+    }
+    BodyEnd.send(stream)
+}
+
+// A body that is already in memory, such as a log or a small source.
+pub fn send_bytes(stream: &mut impl FrameWrite, bytes: &[u8]) -> io::Result<()> {
+    send_body(stream, &mut Cursor::new(bytes), bytes.len() as u64, 0, "")
+}
+
+pub fn begin_body(stream: &mut impl FrameRead) -> io::Result<BodyBegin> {
+    BodyBegin::from_message(&Message::read(stream)?)
+}
+
+// Copy the chunks of the body `begin` announced into `sink` as they arrive.
+//
+// The outer error means the connection failed or broke the protocol, with
+// the body incomplete; `sink` has every whole chunk received before that.
+// The inner error means `sink` failed: the rest of the body is still read
+// off the connection, so the next message can be read as usual.
+pub fn receive_body(
+    stream: &mut impl FrameRead,
+    begin: &BodyBegin,
+    sink: &mut impl Write,
+) -> io::Result<io::Result<u64>> {
+    let mut received = begin.offset;
+    let mut written = Ok(());
+    loop {
+        let msg = Message::read(stream)?;
+        match msg.op {
+            OpCode::BodyChunk => {
+                let chunk = BodyChunk::from_message(&msg)?;
+                received += chunk.data.len() as u64;
+                if received > begin.size {
+                    return Err(malformed(format!("body is longer than the {} bytes announced", begin.size)));
+                }
+                if written.is_ok() {
+                    written = sink.write_all(&chunk.data);
+                }
+            }
+            OpCode::BodyEnd if received == begin.size => return Ok(written.map(|_| received)),
+            OpCode::BodyEnd => {
+                return Err(malformed(format!(
+                    "body ended after {} of the {} bytes announced",
+                    received, begin.size
+                )));
+            }
+            op => return Err(malformed(format!("expected a body chunk, got {:?}", op))),
+        }
+    }
+}
+
+// Receive a whole body into memory. Bodies over MAX_BODY_IN_MEMORY are
+// refused before they are read.
+pub fn read_body(stream: &mut impl FrameRead) -> io::Result<Vec<u8>> {
+    let begin = begin_body(stream)?;
+    if begin.size > MAX_BODY_IN_MEMORY {
+        return Err(malformed(format!(
+            "body of {} bytes exceeds the {} byte limit",
+            begin.size, MAX_BODY_IN_MEMORY
+        )));
+    }
+    let mut body = Vec::new();
+    receive_body(stream, &begin, &mut body)??;
+    verify_body(&begin, &mut body.as_slice())?;
+    Ok(body)
+}
+
+// Receive a body into the file at `path` without holding it in memory.
+//
+// The outer error means the connection failed or broke the protocol. The
+// inner one means the file couldn't be written or the body failed its
+// integrity check; the body was still read off the connection. Either way
+// nothing is left at `path`.
+pub fn read_body_to(stream: &mut impl FrameRead, path: &Path) -> io::Result<io::Result<u64>> {
+    let begin = begin_body(stream)?;
+    let mut file = match File::create(path) {
+        Ok(file) => file,
+        Err(e) => return receive_body(stream, &begin, &mut io::sink()).map(|_| Err(e)),
+    };
+    // Read back what was written, so a bad disk is caught as well
+    let received = receive_body(stream, &begin, &mut file).map(|written| {
+        written.and_then(|size| verify_body(&begin, &mut File::open(path)?).map(|_| size))
+    });
+    if !matches!(received, Ok(Ok(_))) {
+        fs::remove_file(path).ok();
+    }
+    received
+}
+
+// SHA-256 of a whole body.
+pub type Digest = [u8; 32];
+
+pub fn digest_reader(body: &mut impl Read) -> io::Result<Digest> {
+    let mut hasher = Sha256::new();
+    io::copy(body, &mut hasher)?;
+    Ok(hasher.finalize().into())
+}
+
+// A body that arrived whole but not as it was sent.
+#[derive(Debug)]
+pub struct IntegrityError {
+    pub expected: Digest,
+    pub actual: Digest,
+}
+
+impl fmt::Display for IntegrityError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let hex = |digest: &Digest| digest[..8].iter().map(|b| format!("{:02x}", b)).collect::<String>();
+        write!(
+            f,
+            "integrity check failed: expected SHA-256 {}..., got {}...",
+            hex(&self.expected),
+            hex(&self.actual)
+        )
+    }
+}
+
+impl std::error::Error for IntegrityError {}
+
+// Check everything `body` has to read against the digest `begin` announced.
+pub fn verify_body(begin: &BodyBegin, body: &mut impl Read) -> io::Result<()> {
+    let actual = digest_reader(body)?;
+    if actual != begin.digest {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            IntegrityError {
+                expected: begin.digest,
+                actual,
+            },
+        ));
+    }
+    Ok(())
+}
+
+// Whether `e` came from `verify_body`: the connection is still usable and
+// sending the body again may work.
+pub fn is_integrity_error(e: &io::Error) -> bool {
+    e.get_ref().is_some_and(|inner| inner.is::<IntegrityError>())
+}
+
+// Where a file is received until all of it has arrived.
+pub fn part_path(path: &Path) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(".part");
+    PathBuf::from(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip<T: Payload + PartialEq + std::fmt::Debug>(value: T) {
+        let msg = value.to_message();
+        assert_eq!(T::from_message(&msg).unwrap(), value);
+    }
+
+    fn submission() -> Submission {
+        Submission {
+            filename: "a.c".to_string(),
+            compiler: "gcc".to_string(),
+            args: vec!["-O2".to_string(), "-DX=1".to_string()],
+            origin: "/src/a.c".to_string(),
+            headers: vec![("/src/a.h".to_string(), b"#define A 1\n".to_vec())],
+        }
+    }
+
+    #[test]
+    fn every_message_round_trips() {
+        round_trip(SubmitFile {
+            priority: Priority::High,
+            submission: submission(),
+        });
+        round_trip(SubmitBatch {
+            priority: Priority::Low,
+            names: vec!["a.c".to_string(), "b.c".to_string()],
+        });
+        round_trip(BatchFile {
+            index: 7,
+            submission: submission(),
+        });
+        round_trip(BatchResult {
+            index: 7,
+            result: FileResult {
+                status: Status::Rejected,
+                name: "a.c".to_string(),
+            },
+        });
+        round_trip(BatchEnd { error: None });
+        round_trip(BatchEnd {
+            error: Some("refused".to_string()),
+        });
+        round_trip(TaskDef {
+            filename: "a.c".to_string(),
+            compiler: "gcc".to_string(),
+            timeout_secs: 120,
+            args: vec!["-c".to_string()],
+            origin: String::new(),
+            headers: Vec::new(),
+            deps: true,
+        });
+        round_trip(TaskResult {
+            status: Status::Succeeded,
+            stdout: "o".to_string(),
+            stderr: "e".to_string(),
+            deps: vec!["/usr/include/stdio.h".to_string()],
+        });
+        round_trip(Welcome {
+            rejection: None,
+            compression: Compression::Zstd,
+        });
+        round_trip(Welcome {
+            rejection: Some("no compiler".to_string()),
+            compression: Compression::None,
+        });
+        round_trip(Resume {
+            transfer: "ab12".to_string(),
+            offset: 1 << 33,
+        });
+        round_trip(BodyBegin {
+            size: 5 << 30,
+            offset: 0,
+            transfer: String::new(),
+            digest: [9; 32],
+        });
+        round_trip(BodyChunk { data: vec![1, 2, 3] });
+        round_trip(BodyEnd);
+        round_trip(Heartbeat);
+        round_trip(Shutdown);
+    }
+
+    #[test]
+    fn truncated_payloads_are_errors() {
+        let payload = BatchResult {
+            index: 1,
+            result: FileResult {
+                status: Status::Failed,
+                name: "a.c".to_string(),
+            },
+        }
+        .to_message()
+        .payload;
+        for len in 0..payload.len() {
+            let msg = Message::new(OpCode::BatchResult, payload[..len].to_vec());
+            assert!(BatchResult::from_message(&msg).is_err(), "accepted {} bytes", len);
+        }
+    }
+
+    #[test]
+    fn oversized_lengths_and_trailing_bytes_are_errors() {
+        let mut out = Encoder::default();
+        out.u16(PROTOCOL_VERSION).u8(1).u32(u32::MAX).string("a.c");
+        assert!(SubmitBatch::from_message(&Message::new(OpCode::SubmitBatch, out.into_inner())).is_err());
+
+        let mut payload = Heartbeat.to_message().payload;
+        payload.push(0);
+        assert!(Heartbeat::from_message(&Message::new(OpCode::Heartbeat, payload)).is_err());
+    }
+
+    #[test]
+    fn wrong_opcode_and_version_are_reported() {
+        assert!(FileResult::from_message(&Heartbeat.to_message()).is_err());
+
+        let mut msg = SubmitFile {
+            priority: Priority::Normal,
+            submission: submission(),
+        }
+        .to_message();
+        msg.payload[0..2].copy_from_slice(&(PROTOCOL_VERSION + 1).to_be_bytes());
+        let err = SubmitFile::from_message(&msg).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+        assert!(err.to_string().contains("protocol version"));
+    }
+
+    #[test]
+    fn frames_over_the_limit_are_refused_unread() {
+        let mut header = vec![OpCode::BatchFile as u8, 0];
+        header.extend_from_slice(&u32::MAX.to_be_bytes());
+        let err = Message::read(&mut header.as_slice()).err().unwrap();
+        assert!(err.to_string().contains("limit"));
+
+        let chunk = BodyChunk {
+            data: vec![0; MAX_FRAME_SIZE + 1],
+        };
+        assert!(chunk.send(&mut Vec::new()).is_err());
+    }
+
+    #[test]
+    fn links_compress_what_they_send_and_count_it() {
+        let body = b"int main(void) { return 0; }\n".repeat(1000);
+        let mut sender = Link::new(Vec::new(), Compression::Zstd);
+        send_bytes(&mut sender, &body).unwrap();
+        Heartbeat.send(&mut sender).unwrap();
+        let sent = sender.stats();
+        assert!(sent.sent_raw.load(Ordering::Relaxed) > body.len() as u64);
+        assert!(sent.sent_wire.load(Ordering::Relaxed) < body.len() as u64 / 10);
+
+        // Frames say whether they are compressed, so any reader can take them
+        let wire = sender.get_ref().clone();
+        assert_eq!(read_body(&mut wire.as_slice()).unwrap(), body);
+
+        let mut receiver = Link::new(wire.as_slice(), Compression::None);
+        assert_eq!(read_body(&mut receiver).unwrap(), body);
+        assert_eq!(Message::read(&mut receiver).unwrap().op, OpCode::Heartbeat);
+        let received = receiver.stats();
+        assert_eq!(received.received_raw.load(Ordering::Relaxed), sent.sent_raw.load(Ordering::Relaxed));
+        assert_eq!(received.received_wire.load(Ordering::Relaxed), sent.sent_wire.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn bad_compressed_frames_are_errors() {
+        let mut frame = BodyChunk { data: vec![7; 4096] }.to_message().serialize_with(Compression::Zstd);
+        assert_eq!(frame[1], FLAG_ZSTD);
+        frame[1] = 0x80;
+        let err = Message::read(&mut frame.as_slice()).err().unwrap();
+        assert!(err.to_string().contains("flags"));
+
+        frame[1] = FLAG_ZSTD;
+        let last = frame.len() - 4;
+        frame[last..].copy_from_slice(&[0xde, 0xad, 0xbe, 0xef]);
+        let err = Message::read(&mut frame.as_slice()).err().unwrap();
+        assert!(err.to_string().contains("decompress"));
+    }
+
+    #[test]
+    fn bodies_arrive_in_chunks() {
+        let body: Vec<u8> = (0..CHUNK_SIZE * 2 + 10).map(|i| i as u8).collect();
+        let mut wire = Vec::new();
+        send_bytes(&mut wire, &body).unwrap();
+        // Begin, three chunks, end
+        let mut frames = wire.as_slice();
+        let mut ops = Vec::new();
+        while !frames.is_empty() {
+            ops.push(Message::read(&mut frames).unwrap().op);
+        }
+        assert_eq!(ops.len(), 5);
+
+        assert_eq!(read_body(&mut wire.as_slice()).unwrap(), body);
+    }
+
+    #[test]
+    fn broken_bodies_are_errors() {
+        let mut wire = Vec::new();
+        send_bytes(&mut wire, &[7; 100]).unwrap();
+
+        // Cut off before BodyEnd
+        let cut = &wire[..wire.len() - HEADER_SIZE];
+        assert!(read_body(&mut &cut[..]).is_err());
+
+        // More bytes than announced
+        let mut wire = Vec::new();
+        BodyBegin {
+            size: 2,
+            offset: 0,
+            transfer: String::new(),
+            digest: [0; 32],
+        }
+        .send(&mut wire)
+        .unwrap();
+        BodyChunk { data: vec![1, 2, 3] }.send(&mut wire).unwrap();
+        BodyEnd.send(&mut wire).unwrap();
+        assert!(read_body(&mut wire.as_slice()).is_err());
+    }
+
+    #[test]
+    fn corrupted_bodies_fail_their_integrity_check() {
+        let mut wire = Vec::new();
+        send_bytes(&mut wire, b"int main(void) { return 0; }").unwrap();
+        Heartbeat.send(&mut wire).unwrap();
+        let position = wire.windows(4).position(|w| w == b"main").unwrap();
+        wire[position] = b'M';
+
+        let mut stream = wire.as_slice();
+        let err = read_body(&mut stream).unwrap_err();
+        assert!(is_integrity_error(&err), "{}", err);
+        assert!(err.to_string().contains("integrity check failed"));
+        // The whole body was read, so the connection is still in step
+        assert_eq!(Message::read(&mut stream).unwrap().op, OpCode::Heartbeat);
+
+        assert!(!is_integrity_error(&read_body(&mut &wire[..10]).unwrap_err()));
+    }
+
+    #[test]
+    fn bodies_can_go_straight_to_a_file() {
+        let path = std::env::temp_dir().join(format!("dbs-body-{}", std::process::id()));
+        let body: Vec<u8> = (0..CHUNK_SIZE + 10).map(|i| i as u8).collect();
+        let mut wire = Vec::new();
+        send_bytes(&mut wire, &body).unwrap();
+        assert_eq!(read_body_to(&mut wire.as_slice(), &path).unwrap().unwrap(), body.len() as u64);
+        assert_eq!(fs::read(&path).unwrap(), body);
+
+        // A corrupted body is read off the connection but not kept
+        let last = wire.len() - HEADER_SIZE - 1;
+        wire[last] ^= 1;
+        Heartbeat.send(&mut wire).unwrap();
+        let mut stream = wire.as_slice();
+        let err = read_body_to(&mut stream, &path).unwrap().unwrap_err();
+        assert!(is_integrity_error(&err), "{}", err);
+        assert!(!path.exists());
+        assert_eq!(Message::read(&mut stream).unwrap().op, OpCode::Heartbeat);
+    }
+
+    #[test]
+    fn body_resumes_from_its_offset() {
+        let body = b"0123456789";
+        let mut wire = Vec::new();
+        send_body(&mut wire, &mut Cursor::new(body), 10, 4, "t1").unwrap();
+
+        let mut stream = wire.as_slice();
+        let begin = begin_body(&mut stream).unwrap();
+        assert_eq!((begin.offset, begin.transfer.as_str()), (4, "t1"));
+        let mut rest = Vec::new();
+        assert_eq!(receive_body(&mut stream, &begin, &mut rest).unwrap().unwrap(), 10);
+        assert_eq!(rest, b"456789");
+
+        // The digest covers what arrived earlier too
+        let mut whole = body[..4].to_vec();
+        whole.extend_from_slice(&rest);
+        verify_body(&begin, &mut whole.as_slice()).unwrap();
+        assert!(verify_body(&begin, &mut rest.as_slice()).is_err());
+    }
 
-        Ok(Message { op, payload })
+    #[test]
+    fn part_path_appends_to_the_name() {
+        assert_eq!(part_path(Path::new("obj/a.o")), PathBuf::from("obj/a.o.part"));
     }
 }
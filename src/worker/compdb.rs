@@ -0,0 +1,198 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde_json::Value;
+
+use crate::utils::flags::{normalize_path, prepare_args_in};
+
+// Options that only make the compiler write dependency files. The worker
+// can't send those back, so they are dropped rather than refused.
+const DEPFILE_OPTIONS: [&str; 3] = ["-MD", "-MMD", "-MP"];
+const DEPFILE_VALUE_OPTIONS: [&str; 3] = ["-MF", "-MT", "-MQ"];
+
+// One entry of a compilation database, turned into what a task needs.
+#[derive(Debug, PartialEq)]
+pub struct CompileEntry {
+    pub source: PathBuf,
+    pub output: PathBuf,
+    pub compiler: String,
+    // Prepared like `flags::prepare_args`, relative to the entry's directory
+    pub args: Vec<String>,
+}
+
+// Read a `compile_commands.json` as written by CMake, Bear and friends.
+pub fn load(path: &Path) -> Result<Vec<CompileEntry>, String> {
+    let text = fs::read_to_string(path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    let json: Value = serde_json::from_str(&text).map_err(|e| format!("Failed to parse {}: {}", path.display(), e))?;
+    let entries = json
+        .as_array()
+        .ok_or_else(|| format!("{} is not a list of compile commands", path.display()))?;
+
+    entries
+        .iter()
+        .enumerate()
+        .map(|(i, entry)| parse_entry(entry).map_err(|e| format!("{} entry {}: {}", path.display(), i, e)))
+        .collect()
+}
+
+fn parse_entry(entry: &Value) -> Result<CompileEntry, String> {
+    let field = |name: &str| entry.get(name).and_then(Value::as_str);
+
+    let directory = PathBuf::from(field("directory").ok_or("missing \"directory\"")?);
+    let file = field("file").ok_or("missing \"file\"")?;
+    let argv: Vec<String> = match (entry.get("arguments"), field("command")) {
+        (Some(Value::Array(args)), _) => args
+            .iter()
+            .map(|a| a.as_str().map(str::to_string).ok_or("non-string argument"))
+            .collect::<Result<_, _>>()?,
+        (_, Some(command)) => split_command(command)?,
+        _ => return Err("missing \"arguments\" or \"command\"".to_string()),
+    };
+    let (compiler, args) = argv.split_first().ok_or("empty command")?;
+
+    let source = normalize_path(&directory.join(file));
+    let mut output = field("output").map(|o| normalize_path(&directory.join(o)));
+    let mut kept = Vec::new();
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "-o" => {
+                let value = iter.next().ok_or("'-o' is missing its path argument")?;
+                output.get_or_insert_with(|| normalize_path(&directory.join(value)));
+            }
+            glued if glued.starts_with("-o") => {
+                output.get_or_insert_with(|| normalize_path(&directory.join(&glued[2..])));
+            }
+            dep if DEPFILE_OPTIONS.contains(&dep) => {}
+            dep if DEPFILE_VALUE_OPTIONS.contains(&dep) => {
+                iter.next();
+            }
+            dep if DEPFILE_VALUE_OPTIONS.iter().any(|opt| dep.starts_with(opt)) => {}
+            // The source itself; the worker passes its own copy
+            file if normalize_path(&directory.join(file)) == source => {}
+            _ => kept.push(arg.clone()),
+        }
+    }
+
+    // The worker compiles a copy of the source, so point quoted includes
+    // back at the directory they would have been found in
+    let mut prepared = Vec::new();
+    if let Some(parent) = source.parent() {
+        prepared.push("-iquote".to_string());
+        prepared.push(parent.to_string_lossy().to_string());
+    }
+    prepared.extend(prepare_args_in(&directory, &kept)?);
+
+    Ok(CompileEntry {
+        output: output.unwrap_or_else(|| source.with_extension("o")),
+        source,
+        compiler: compiler.clone(),
+        args: prepared,
+    })
+}
+
+// Split a "command" string the way a POSIX shell would split plain words:
+// whitespace separates, quotes group and backslash escapes.
+fn split_command(command: &str) -> Result<Vec<String>, String> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    let mut in_word = false;
+    let mut chars = command.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                word.push(chars.next().ok_or("trailing backslash in command")?);
+                in_word = true;
+            }
+            '\'' => {
+                loop {
+                    match chars.next().ok_or("unterminated ' in command")? {
+                        '\'' => break,
+                        c => word.push(c),
+                    }
+                }
+                in_word = true;
+            }
+            '"' => {
+                loop {
+                    match chars.next().ok_or("unterminated \" in command")? {
+                        '"' => break,
+                        '\\' => word.push(chars.next().ok_or("unterminated \" in command")?),
+                        c => word.push(c),
+                    }
+                }
+                in_word = true;
+            }
+            c if c.is_whitespace() => {
+                if in_word {
+                    words.push(std::mem::take(&mut word));
+                    in_word = false;
+                }
+            }
+            c => {
+                word.push(c);
+                in_word = true;
+            }
+        }
+    }
+    if in_word {
+        words.push(word);
+    }
+    Ok(words)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn split_command_handles_quotes_and_escapes() {
+        assert_eq!(
+            split_command(r#"cc -DNAME="\"dbs\"" -I 'my dir' a\ b.c  -c"#).unwrap(),
+            vec!["cc", r#"-DNAME="dbs""#, "-I", "my dir", "a b.c", "-c"]
+        );
+        assert!(split_command("cc 'open").is_err());
+    }
+
+    #[test]
+    fn entry_keeps_flags_and_output_path() {
+        let entry = json!({
+            "directory": "/proj/build",
+            "arguments": ["/usr/bin/cc", "-DX=1", "-I../include", "-O2", "-MD", "-MT", "obj/a.o",
+                          "-MF", "obj/a.o.d", "-o", "obj/a.o", "-c", "../src/a.c"],
+            "file": "../src/a.c",
+        });
+        assert_eq!(
+            parse_entry(&entry).unwrap(),
+            CompileEntry {
+                source: PathBuf::from("/proj/src/a.c"),
+                output: PathBuf::from("/proj/build/obj/a.o"),
+                compiler: "/usr/bin/cc".to_string(),
+                args: ["-iquote", "/proj/src", "-DX=1", "-I/proj/include", "-O2"].map(String::from).to_vec(),
+            }
+        );
+    }
+
+    #[test]
+    fn entry_from_command_without_output() {
+        let entry = json!({
+            "directory": "/proj",
+            "command": "gcc -c -std=c11 main.c",
+            "file": "main.c",
+        });
+        let entry = parse_entry(&entry).unwrap();
+        assert_eq!(entry.output, PathBuf::from("/proj/main.o"));
+        assert_eq!(entry.args, ["-iquote", "/proj", "-std=c11"].map(String::from).to_vec());
+    }
+
+    #[test]
+    fn entry_with_unsafe_flags_is_refused() {
+        let entry = json!({
+            "directory": "/proj",
+            "arguments": ["gcc", "-fplugin=evil.so", "-c", "main.c"],
+            "file": "main.c",
+        });
+        assert!(parse_entry(&entry).is_err());
+    }
+}
@@ -1,25 +1,118 @@
+use std::collections::HashMap;
 use std::env;
 use std::net::TcpListener;
+use std::path::{Path, PathBuf};
 use std::process::{Command, Stdio};
 use std::sync::{Arc, Mutex};
 use std::thread;
 
 use crate::utils::config;
-use crate::server::session::handle_worker_session;
+use crate::utils::flags::prepare_args;
+use crate::utils::net::Connection;
+use crate::utils::protocol::{Message, OpCode, Status};
+use crate::server::jobs::{JobEvent, JobTable};
+use crate::server::queue::TaskQueue;
+use crate::server::session::{WorkerRegistry, handle_worker_session};
+use crate::server::task::Task;
+use super::compdb::{self, CompileEntry};
+use super::incremental::{self, BuildState, Incremental};
+use super::link::{LinkTarget, link};
 use super::workload::{determine_workload, validate_worker_count};
 
-pub fn controller_node(files: Vec<String>) {
+// Builds `files`, or every entry of the `compdb` compilation database,
+// skipping objects that `incremental` finds up to date.
+// Returns true when every file compiled successfully and, if a `link`
+// target was given, the objects were linked into it.
+pub fn controller_node(
+    files: Vec<String>,
+    compdb: Option<PathBuf>,
+    cc_args: &[String],
+    link_target: Option<LinkTarget>,
+    incremental: Incremental,
+) -> bool {
     let server_addr = config::get_server_addr();
     let worker_count = validate_worker_count(config::get_worker_count());
     
     println!("[Cluster] Starting Build Server on {}", server_addr);
     println!("[Cluster] Using {} worker processes", worker_count);
 
-    let workload = determine_workload(files);
+    let compiler = config::get_default_compiler();
+    let mut cc_args = match prepare_args(cc_args) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            return false;
+        }
+    };
+    if let Some(target) = &link_target {
+        for arg in target.compile_args() {
+            if !cc_args.iter().any(|a| a == arg) {
+                cc_args.push(arg.to_string());
+            }
+        }
+    }
+    println!("[Cluster] Compiling with {} {}", compiler, cc_args.join(" "));
+
+    // Database entries keep their own compiler, flags and output path;
+    // `--` arguments are added to them
+    let units: Vec<CompileEntry> = match compdb {
+        Some(path) => match compdb::load(&path) {
+            Ok(entries) => {
+                println!("[Cluster] Using {} entries from {}", entries.len(), path.display());
+                entries
+                    .into_iter()
+                    .map(|mut entry| {
+                        entry.args.extend(cc_args.iter().cloned());
+                        entry
+                    })
+                    .collect()
+            }
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                return false;
+            }
+        },
+        None => determine_workload(files)
+            .into_iter()
+            .map(|file| CompileEntry {
+                output: Path::new(&file).with_extension("o"),
+                source: PathBuf::from(file),
+                compiler: compiler.to_string(),
+                args: cc_args.clone(),
+            })
+            .collect(),
+    };
+    
+    // Local workers must offer every compiler the workload asks for
+    let mut compilers: Vec<&str> = units.iter().map(|u| u.compiler.as_str()).collect();
+    compilers.sort();
+    compilers.dedup();
+    
+    let mut state = BuildState::load();
+    let stale: Vec<usize> = (0..units.len())
+        .filter(|&i| !incremental::is_up_to_date(incremental, &units[i], &state))
+        .collect();
+    let up_to_date = units.len() - stale.len();
+    
+    let jobs = Arc::new(JobTable::new());
+    let mut pending = Vec::new();
+    let mut workload = TaskQueue::new();
+    for &index in &stale {
+        let unit = &units[index];
+        let (id, done) = jobs.register();
+        let path = unit.source.to_string_lossy().to_string();
+        pending.push((index, done));
+        let mut task = Task::new(id, path, unit.compiler.clone(), unit.args.clone())
+            .with_output(unit.output.to_string_lossy().to_string());
+        // The headers it includes, for the next build to check
+        if incremental != Incremental::Off {
+            task = task.with_depfile(incremental::depfile_path(&unit.output).to_string_lossy().to_string());
+        }
+        workload.push(task);
+    }
     let total_tasks = workload.len();
     let queue = Arc::new(Mutex::new(workload));
-    // Store results as (Filename, Success_Bool, Message)
-    let results = Arc::new(Mutex::new(Vec::new()));
+    let workers: WorkerRegistry = Arc::new(Mutex::new(HashMap::new()));
 
     let listener = TcpListener::bind(server_addr).expect("Bind failed");
 
@@ -32,6 +125,13 @@ pub fn controller_node(files: Vec<String>) {
         let child = Command::new(&current_exe)
             .arg("worker")
             .arg(i.to_string())
+            .arg("--server")
+            .arg(server_addr)
+            // Local workers must offer the workload's compilers even if they
+            // aren't ones they would detect on their own
+            .args(compilers.iter().flat_map(|cc| ["--compiler", cc]))
+            .arg("--no-compression")
+            .arg("--local-build")
             .stdout(Stdio::inherit())
             .stderr(Stdio::inherit())
             .spawn()
@@ -43,15 +143,25 @@ pub fn controller_node(files: Vec<String>) {
     let mut handles = Vec::new();
     println!("[Cluster] Waiting for workers to connect...");
 
-    for _ in 0..worker_count {
-        let (stream, addr) = listener.accept().unwrap();
+    while handles.len() < worker_count {
+        // Its own workers, on this machine: no TLS
+        let (tcp, addr) = listener.accept().unwrap();
+        let mut stream = Connection::plain(tcp);
+        let hello = match Message::read(&mut stream) {
+            Ok(msg) if msg.op == OpCode::Hello => msg,
+            _ => {
+                eprintln!("[Cluster] Ignoring non-worker connection from {}", addr);
+                continue;
+            }
+        };
         println!("[Cluster] Worker connected from {}", addr);
 
         let q_clone = Arc::clone(&queue);
-        let r_clone = Arc::clone(&results);
+        let j_clone = Arc::clone(&jobs);
+        let w_clone = Arc::clone(&workers);
 
         let handle = thread::spawn(move || {
-            handle_worker_session(stream, q_clone, r_clone);
+            handle_worker_session(stream, hello, q_clone, j_clone, w_clone, true);
         });
         handles.push(handle);
     }
@@ -59,22 +169,93 @@ pub fn controller_node(files: Vec<String>) {
     for h in handles {
         h.join().unwrap();
     }
-    for mut child in children {
-        child.wait().unwrap();
+
+    // Workers exit on Shutdown; reap them so none are left behind as zombies.
+    for (i, mut child) in children.into_iter().enumerate() {
+        match child.wait() {
+            Ok(status) if !status.success() => {
+                eprintln!("[Cluster] Worker #{} exited with {}", i, status);
+            }
+            Ok(_) => {}
+            Err(e) => eprintln!("[Cluster] Failed to reap worker #{}: {}", i, e),
+        }
     }
 
-    // 6. Report
-    let final_results = results.lock().unwrap();
+    // 6. Report. Every job that was dispatched has its result by now.
+    // Store results as (Filename, Status, Message)
+    let final_results: Vec<(usize, Status, String)> = pending
+        .into_iter()
+        .filter_map(|(index, done)| {
+            done.try_iter().find_map(|event| match event {
+                JobEvent::Finished(r) => Some((index, r.status, r.log)),
+                JobEvent::Requeued => None,
+            })
+        })
+        .collect();
+    
+    // Note what the new objects were built from, for the next build
+    let rebuilt: Vec<usize> = final_results
+        .iter()
+        .filter(|r| r.1 == Status::Succeeded)
+        .map(|r| r.0)
+        .collect();
+    for &index in &rebuilt {
+        match incremental::record(incremental, &units[index]) {
+            Ok(Some(hash)) => state.set(&units[index], hash),
+            Ok(None) => {}
+            Err(e) => eprintln!("[Cluster] Failed to record the inputs of {}: {}", units[index].source.display(), e),
+        }
+    }
+    if incremental != Incremental::Off
+        && let Err(e) = state.save()
+    {
+        eprintln!("[Cluster] Failed to save the build state: {}", e);
+    }
+    
     println!("\n=== BUILD REPORT ===");
-    let success_count = final_results.iter().filter(|r| r.1).count();
+    let success_count = rebuilt.len();
     println!(
         "Build Complete: {}/{} Succeeded.",
         success_count, total_tasks
     );
+    println!("{} up to date, {} rebuilt.", up_to_date, success_count);
 
     if success_count == total_tasks {
         println!("All files compiled successfully to .o files.");
+        let Some(target) = link_target else {
+            return true;
+        };
+        
+        // Relink only when an object changed or the output is gone
+        if success_count == 0 && target.output.exists() {
+            println!("Link up to date: {}", target.output.display());
+            return true;
+        }
+        let objects: Vec<PathBuf> = units.iter().map(|u| u.output.clone()).collect();
+        println!("[Cluster] Linking {} objects into {}...", objects.len(), target.output.display());
+        match link(&target, &objects, compiler) {
+            Ok(()) => {
+                println!("Linked {}: {}", target.describe(), target.output.display());
+                true
+            }
+            Err(log) => {
+                println!("LINK FAILED: {}\n{}", target.output.display(), log.trim_end());
+                false
+            }
+        }
     } else {
+        for (index, status, log) in final_results.iter().filter(|r| r.1 != Status::Succeeded) {
+            let label = if *status == Status::TimedOut { "TIMED OUT" } else { "FAILED" };
+            println!("{}: {}\n{}", label, units[*index].source.display(), log.trim_end());
+        }
+        // Left over when no accepted worker had the right compiler
+        for task in queue.lock().unwrap().iter() {
+            println!("NOT BUILT: {} (no worker offers {})", task.path, task.compiler);
+        }
         println!("Some files failed. Check stdout for details.");
+        if let Some(target) = link_target {
+            println!("NOT LINKED: {}", target.output.display());
+        }
+        false
     }
 }
@@ -0,0 +1,230 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use clap::ValueEnum;
+use sha2::{Digest, Sha256};
+
+use crate::utils::depfile;
+use super::compdb::CompileEntry;
+
+// Hashes of what each object was last built from.
+const STATE_FILE: &str = ".dbs/state";
+
+// How `dbs build` decides that an object can be kept.
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum Incremental {
+    /// Rebuild every object
+    Off,
+    /// Keep objects newer than their source and every header it includes,
+    /// built with the same compiler and flags
+    Mtime,
+    /// Keep objects whose source, headers, compiler and flags hash the same as last time
+    Hash,
+}
+
+// `.dbs/state`: one `<hash> <object path>` line per object built. In hash
+// mode the hash covers every input, in mtime mode the compiler and flags.
+#[derive(Default)]
+pub struct BuildState {
+    hashes: HashMap<String, String>,
+}
+
+impl BuildState {
+    // A missing or unreadable state just means everything is rebuilt.
+    pub fn load() -> Self {
+        let text = fs::read_to_string(STATE_FILE).unwrap_or_default();
+        let hashes = text
+            .lines()
+            .filter_map(|line| line.split_once(' '))
+            .map(|(hash, output)| (output.to_string(), hash.to_string()))
+            .collect();
+        Self { hashes }
+    }
+
+    pub fn save(&self) -> io::Result<()> {
+        let mut lines: Vec<String> = self
+            .hashes
+            .iter()
+            .map(|(output, hash)| format!("{} {}\n", hash, output))
+            .collect();
+        lines.sort();
+        if let Some(dir) = Path::new(STATE_FILE).parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(STATE_FILE, lines.concat())
+    }
+
+    pub fn set(&mut self, unit: &CompileEntry, hash: String) {
+        self.hashes.insert(unit.output.to_string_lossy().to_string(), hash);
+    }
+
+    fn matches(&self, unit: &CompileEntry, hash: &str) -> bool {
+        self.hashes.get(unit.output.to_string_lossy().as_ref()).is_some_and(|h| h == hash)
+    }
+}
+
+// Dependency file kept next to each object, where `-MD` would put it. The
+// session writes it from the headers the worker's compile read.
+pub fn depfile_path(output: &Path) -> PathBuf {
+    output.with_extension("d")
+}
+
+// Whether `unit`'s object can be kept as it is.
+pub fn is_up_to_date(mode: Incremental, unit: &CompileEntry, state: &BuildState) -> bool {
+    if mode == Incremental::Off {
+        return false;
+    }
+    // Without the dependency list there's no telling which headers matter
+    let deps = match depfile::read(&depfile_path(&unit.output)) {
+        Ok(deps) if !deps.is_empty() => deps,
+        _ => return false,
+    };
+
+    match mode {
+        Incremental::Off => false,
+        Incremental::Mtime => {
+            let Ok(built) = modified(&unit.output) else {
+                return false;
+            };
+            state.matches(unit, &command_hash(unit))
+                && modified(&unit.source).is_ok_and(|t| t <= built)
+                && deps.iter().all(|dep| modified(Path::new(dep)).is_ok_and(|t| t <= built))
+        }
+        Incremental::Hash => {
+            unit.output.exists() && input_hash(unit, &deps).is_some_and(|hash| state.matches(unit, &hash))
+        }
+    }
+}
+
+// After `unit` compiled and its depfile was written, the hash to store for
+// it in `.dbs/state`.
+pub fn record(mode: Incremental, unit: &CompileEntry) -> Result<Option<String>, String> {
+    match mode {
+        Incremental::Off => Ok(None),
+        Incremental::Mtime => Ok(Some(command_hash(unit))),
+        Incremental::Hash => {
+            let depfile = depfile_path(&unit.output);
+            let deps = depfile::read(&depfile).map_err(|e| format!("Failed to read {}: {}", depfile.display(), e))?;
+            input_hash(unit, &deps)
+                .map(Some)
+                .ok_or_else(|| format!("Failed to read the inputs of {}", unit.source.display()))
+        }
+    }
+}
+
+fn modified(path: &Path) -> io::Result<SystemTime> {
+    fs::metadata(path)?.modified()
+}
+
+// Length-prefixed fields, so no two different lists hash the same.
+struct FieldHasher(Sha256);
+
+impl FieldHasher {
+    // Starts with how `unit` is compiled: its compiler and flags.
+    fn new(unit: &CompileEntry) -> Self {
+        let mut hasher = Self(Sha256::new());
+        hasher.field(unit.compiler.as_bytes());
+        for arg in &unit.args {
+            hasher.field(arg.as_bytes());
+        }
+        hasher
+    }
+
+    fn field(&mut self, bytes: &[u8]) {
+        self.0.update((bytes.len() as u64).to_be_bytes());
+        self.0.update(bytes);
+    }
+
+    fn finish(self) -> String {
+        self.0.finalize().iter().map(|b| format!("{:02x}", b)).collect()
+    }
+}
+
+fn command_hash(unit: &CompileEntry) -> String {
+    FieldHasher::new(unit).finish()
+}
+
+// Hash of everything the object depends on. None if an input can't be read.
+fn input_hash(unit: &CompileEntry, deps: &[String]) -> Option<String> {
+    let mut hasher = FieldHasher::new(unit);
+    hasher.field(unit.source.to_string_lossy().as_bytes());
+    hasher.field(&fs::read(&unit.source).ok()?);
+    for dep in deps {
+        hasher.field(dep.as_bytes());
+        hasher.field(&fs::read(dep).ok()?);
+    }
+    Some(hasher.finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    fn unit_in(dir: &Path) -> CompileEntry {
+        CompileEntry {
+            source: dir.join("a.c"),
+            output: dir.join("a.o"),
+            compiler: "cc".to_string(),
+            args: vec!["-O2".to_string()],
+        }
+    }
+
+    #[test]
+    fn hash_follows_inputs_and_flags() {
+        let dir = env::temp_dir().join(format!("dbs-incremental-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let mut unit = unit_in(&dir);
+        let header = dir.join("a.h").to_string_lossy().to_string();
+        fs::write(&unit.source, "#include \"a.h\"\n").unwrap();
+        fs::write(&header, "#define A 1\n").unwrap();
+
+        let deps = vec![unit.source.to_string_lossy().to_string(), header.clone()];
+        let first = input_hash(&unit, &deps).unwrap();
+        assert_eq!(input_hash(&unit, &deps), Some(first.clone()));
+
+        fs::write(&header, "#define A 2\n").unwrap();
+        let second = input_hash(&unit, &deps).unwrap();
+        assert_ne!(first, second);
+
+        unit.args.push("-g".to_string());
+        assert_ne!(input_hash(&unit, &deps), Some(second));
+
+        fs::remove_file(&header).unwrap();
+        assert_eq!(input_hash(&unit, &deps), None);
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn mtime_mode_follows_flags_too() {
+        let dir = env::temp_dir().join(format!("dbs-incremental-mtime-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let mut unit = unit_in(&dir);
+        let source = unit.source.to_string_lossy().to_string();
+        fs::write(&unit.source, "int a;\n").unwrap();
+        fs::write(depfile_path(&unit.output), depfile::make_rule("a.o", &[source])).unwrap();
+        fs::write(&unit.output, "").unwrap();
+
+        let mut state = BuildState::default();
+        assert!(!is_up_to_date(Incremental::Mtime, &unit, &state));
+        state.set(&unit, record(Incremental::Mtime, &unit).unwrap().unwrap());
+        assert!(is_up_to_date(Incremental::Mtime, &unit, &state));
+
+        unit.args.push("-g".to_string());
+        assert!(!is_up_to_date(Incremental::Mtime, &unit, &state));
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn nothing_is_up_to_date_without_a_depfile() {
+        let dir = env::temp_dir().join(format!("dbs-incremental-none-{}", std::process::id()));
+        let unit = unit_in(&dir);
+        let state = BuildState::default();
+        for mode in [Incremental::Off, Incremental::Mtime, Incremental::Hash] {
+            assert!(!is_up_to_date(mode, &unit, &state));
+        }
+    }
+}
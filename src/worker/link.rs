@@ -0,0 +1,117 @@
+use std::path::PathBuf;
+use std::process::Command;
+
+use crate::utils::config;
+use super::run_with_timeout;
+
+// What `dbs build` makes of the objects once they are all compiled.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LinkKind {
+    Executable,
+    StaticLib,
+    SharedLib,
+}
+
+pub struct LinkTarget {
+    pub kind: LinkKind,
+    pub output: PathBuf,
+    // Passed to the linker after the objects, e.g. `-lm`
+    pub args: Vec<String>,
+}
+
+impl LinkTarget {
+    pub fn describe(&self) -> &'static str {
+        match self.kind {
+            LinkKind::Executable => "executable",
+            LinkKind::StaticLib => "static library",
+            LinkKind::SharedLib => "shared library",
+        }
+    }
+
+    // Objects going into a shared library must be position independent.
+    pub fn compile_args(&self) -> &'static [&'static str] {
+        match self.kind {
+            LinkKind::SharedLib => &["-fPIC"],
+            _ => &[],
+        }
+    }
+}
+
+// Link `objects` into `target.output` on this machine. Returns the linker's
+// output as the error on failure.
+pub fn link(target: &LinkTarget, objects: &[PathBuf], compiler: &str) -> Result<(), String> {
+    // ar adds to an existing archive, which would keep stale members
+    if target.kind == LinkKind::StaticLib && target.output.exists() {
+        std::fs::remove_file(&target.output)
+            .map_err(|e| format!("Failed to replace {}: {}", target.output.display(), e))?;
+    }
+    let command = link_command(target, objects, compiler);
+    match run_with_timeout(command, config::get_exec_timeout()) {
+        Ok(Some(out)) if out.status.success() => Ok(()),
+        Ok(Some(out)) => Err(format!(
+            "{}{}",
+            String::from_utf8_lossy(&out.stdout),
+            String::from_utf8_lossy(&out.stderr)
+        )),
+        Ok(None) => Err(format!(
+            "Linking exceeded {} s and was killed",
+            config::get_exec_timeout().as_secs()
+        )),
+        Err(e) => Err(format!("Failed to run the linker: {}", e)),
+    }
+}
+
+fn link_command(target: &LinkTarget, objects: &[PathBuf], compiler: &str) -> Command {
+    match target.kind {
+        // Archives take no linker arguments; the CLI refuses them
+        LinkKind::StaticLib => {
+            let mut command = Command::new("ar");
+            command.arg("rcs").arg(&target.output).args(objects);
+            command
+        }
+        LinkKind::Executable | LinkKind::SharedLib => {
+            let mut command = Command::new(compiler);
+            if target.kind == LinkKind::SharedLib {
+                command.arg("-shared");
+            }
+            command.args(objects).args(&target.args).arg("-o").arg(&target.output);
+            command
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn argv(kind: LinkKind, output: &str) -> Vec<String> {
+        let target = LinkTarget {
+            kind,
+            output: PathBuf::from(output),
+            args: vec!["-lm".to_string()],
+        };
+        let command = link_command(&target, &[PathBuf::from("a.o"), PathBuf::from("b.o")], "clang");
+        std::iter::once(command.get_program())
+            .chain(command.get_args())
+            .map(|arg| arg.to_string_lossy().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn each_target_kind_gets_its_own_command() {
+        assert_eq!(argv(LinkKind::Executable, "app"), ["clang", "a.o", "b.o", "-lm", "-o", "app"]);
+        assert_eq!(
+            argv(LinkKind::SharedLib, "libfoo.so"),
+            ["clang", "-shared", "a.o", "b.o", "-lm", "-o", "libfoo.so"]
+        );
+        assert_eq!(argv(LinkKind::StaticLib, "libfoo.a"), ["ar", "rcs", "libfoo.a", "a.o", "b.o"]);
+    }
+
+    #[test]
+    fn only_shared_libraries_need_position_independent_code() {
+        let target = |kind| LinkTarget { kind, output: PathBuf::new(), args: Vec::new() };
+        assert_eq!(target(LinkKind::SharedLib).compile_args(), ["-fPIC"]);
+        assert!(target(LinkKind::Executable).compile_args().is_empty());
+        assert!(target(LinkKind::StaticLib).compile_args().is_empty());
+    }
+}
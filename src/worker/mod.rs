@@ -1,68 +1,380 @@
+pub mod compdb;
 pub mod controller;
+pub mod incremental;
+pub mod link;
 pub(crate) mod workload;
 
-use std::io::Write;
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, Read};
 use std::net::TcpStream;
-use std::process::Command;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Output, Stdio};
+use std::sync::mpsc::{self, RecvTimeoutError};
 use std::thread;
+use std::time::{Duration, Instant};
 
 use crate::utils::config;
-use crate::utils::protocol::{Message, OpCode};
+use crate::utils::depfile;
+use crate::utils::flags::{check_forced_includes, check_remote_args, rewrite_for_sandbox, sandbox_path};
+use crate::utils::handshake::WorkerInfo;
+use crate::utils::net::{Connection, LOCAL_WORKER_SECRET_VAR};
+use crate::utils::protocol::{
+    FrameWrite, Heartbeat, Link, Message, OpCode, Payload, Status, TaskDef, TaskResult, Welcome, is_integrity_error,
+    read_body_to, send_body, send_bytes,
+};
 
-pub fn worker_node(id: &str) {
+// `compress` offers the controller compressed payloads; not worth it over
+// loopback. `local_build` workers compile the files of a `dbs build` on
+// their own machine, so tasks may force-include any file on it.
+pub fn worker_node(id: &str, extra_compilers: &[String], compress: bool, local_build: bool) {
     let server_addr = config::get_server_addr();
-    
-    let mut stream = loop {
+
+    let tcp = loop {
         match TcpStream::connect(server_addr) {
             Ok(s) => break s,
             Err(_) => thread::sleep(std::time::Duration::from_millis(100)),
         }
     };
+    let mut stream = match Connection::to_server(tcp, server_addr) {
+        Ok(stream) => stream,
+        Err(e) => {
+            eprintln!("\t[Worker #{}] Failed to connect to {}: {}", id, server_addr, e);
+            std::process::exit(1);
+        }
+    };
+
+    let mut info = WorkerInfo::local(id, extra_compilers);
+    if !compress {
+        info.compression.clear();
+    }
+    info.send(&mut stream).unwrap();
+
+    let verdict = match Message::read(&mut stream).and_then(|msg| Welcome::from_message(&msg)) {
+        Ok(Welcome { rejection: None, compression }) => Ok(compression),
+        Ok(Welcome { rejection: Some(reason), .. }) => Err(reason),
+        Err(e) => Err(e.to_string()),
+    };
+    let mut stream = match verdict {
+        Ok(compression) => Link::new(stream, compression),
+        Err(reason) => {
+            eprintln!("\t[Worker #{}] Rejected by {}: {}", id, server_addr, reason);
+            std::process::exit(1);
+        }
+    };
 
-    let hello = Message::new(OpCode::Hello, format!("Worker-{}", id).into_bytes());
-    stream.write_all(&hello.serialize()).unwrap();
+    // Sources arrive over the wire, so the worker compiles in its own scratch
+    // directory instead of next to the controller's files.
+    let scratch_dir = env::temp_dir().join(format!("dbs-worker-{}-{}", id, std::process::id()));
+    if let Err(e) = fs::create_dir_all(&scratch_dir) {
+        eprintln!("\t[Worker #{}] Failed to create {}: {}", id, scratch_dir.display(), e);
+        std::process::exit(1);
+    }
+    // Each source is received here before it is moved into place
+    let upload = scratch_dir.join("upload");
 
     while let Ok(msg) = Message::read(&mut stream) {
 
         match msg.op {
             OpCode::TaskDef => {
-                // Payload is the file path string
-                let path = String::from_utf8_lossy(&msg.payload).to_string();
-
-                println!("\t[Worker #{}] Compiling {}...", id, path);
-
-                // EXECUTE GCC
-                // gcc -c path/to/file.c -o path/to/file.o
-                let output_file = path.replace(".c", ".o");
-                let output = Command::new("gcc")
-                    .arg("-c")
-                    .arg(&path)
-                    .arg("-o")
-                    .arg(&output_file)
-                    .output();
-
-                let (success, log) = match output {
-                    Ok(out) => {
-                        let log = if out.status.success() {
-                            "OK".to_string()
+                // The source follows either way; without it the stream is
+                // out of step with the server
+                let task = parse_task(&msg);
+                match read_body_to(&mut stream, &upload) {
+                    Ok(Ok(_)) => {}
+                    // Still in step; the server sends a corrupted task again
+                    Ok(Err(e)) => {
+                        eprintln!("\t[Worker #{}] Source: {}", id, e);
+                        let result = if is_integrity_error(&e) {
+                            TaskResult::failed(Status::Corrupted, &format!("Source on the worker: {}", e))
                         } else {
-                            String::from_utf8_lossy(&out.stderr).to_string()
+                            TaskResult::failed(Status::Failed, &format!("Failed to store source: {}", e))
                         };
-                        (out.status.success(), log)
+                        if send_result(&mut stream, result, None).is_err() {
+                            break;
+                        }
+                        continue;
+                    }
+                    Err(e) => {
+                        eprintln!("\t[Worker #{}] Failed to receive source: {}", id, e);
+                        break;
                     }
-                    Err(e) => (false, e.to_string()), // GCC likely not found
-                };
+                }
 
-                // Serialize: [1 byte bool] [Output String]
-                let mut resp_payload = Vec::new();
-                resp_payload.push(if success { 1 } else { 0 });
-                resp_payload.extend_from_slice(log.as_bytes());
+                let (result, object) = with_heartbeat(&stream, || match task {
+                    // Never run an executable we didn't advertise
+                    Ok(task) if !info.supports(&task.compiler) => {
+                        let reason = format!("Compiler '{}' is not offered by this worker", task.compiler);
+                        (TaskResult::failed(Status::Failed, &reason), None)
+                    }
+                    Ok(task) => match check_remote_args(&task.args) {
+                        Ok(()) => {
+                            println!("\t[Worker #{}] Compiling {} with {}...", id, task.filename, task.compiler);
+                            compile_task(&scratch_dir, &task, &upload, local_build)
+                        }
+                        Err(e) => (TaskResult::failed(Status::Failed, &e), None),
+                    },
+                    Err(e) => (TaskResult::failed(Status::Failed, &e.to_string()), None),
+                });
+                // Left over when the task was refused
+                fs::remove_file(&upload).ok();
 
-                let resp = Message::new(OpCode::TaskResult, resp_payload);
-                stream.write_all(&resp.serialize()).unwrap();
+                if let Err(e) = send_result(&mut stream, result, object) {
+                    eprintln!("\t[Worker #{}] Failed to send result: {}", id, e);
+                    break;
+                }
             }
             OpCode::Shutdown => break,
             _ => {}
         }
     }
+
+    fs::remove_dir_all(&scratch_dir).ok();
+    println!("\t[Worker #{}] Disconnected: {}", id, stream.stats().summary());
+}
+
+// Keep telling the server we're alive while `work` runs, so a long compile
+// isn't mistaken for a hung worker. The heartbeat thread is stopped before
+// returning, so it never writes in the middle of the result.
+fn with_heartbeat<T>(stream: &Link<Connection>, work: impl FnOnce() -> T) -> T {
+    let Ok(mut beat_stream) = stream.try_clone() else {
+        return work();
+    };
+    let (stop, stopped) = mpsc::channel::<()>();
+    let beat = thread::spawn(move || {
+        while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(config::HEARTBEAT_INTERVAL) {
+            if Heartbeat.send(&mut beat_stream).is_err() {
+                break;
+            }
+        }
+    });
+
+    let result = work();
+    drop(stop);
+    beat.join().ok();
+    result
+}
+
+// Decode a TaskDef, keeping only the bare file name: it is used to place
+// the source in the scratch directory.
+fn parse_task(msg: &Message) -> io::Result<TaskDef> {
+    let mut task = TaskDef::from_message(msg)?;
+    task.filename = Path::new(&task.filename)
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("bad source name '{}'", task.filename)))?
+        .to_string();
+    Ok(task)
+}
+
+// Move the source received at `upload` into place, run the compiler on it
+// and report the outcome, with the path of the object file on success.
+fn compile_task(scratch_dir: &Path, spec: &TaskDef, upload: &Path, local_build: bool) -> (TaskResult, Option<PathBuf>) {
+    let output_path = scratch_dir.join(&spec.filename).with_extension("o");
+
+    // Bundled sources are rebuilt under a sandbox at the paths they had on
+    // the client, so `#include "..."` and -I directories line up again.
+    let sandbox = scratch_dir.join("tree");
+    let source_path = if spec.origin.is_empty() {
+        scratch_dir.join(&spec.filename)
+    } else {
+        match sandbox_path(&sandbox, &spec.origin) {
+            Some(path) => path,
+            None => return (TaskResult::failed(Status::Failed, "Refusing unsafe source path"), None),
+        }
+    };
+
+    if let Err(e) = stage_files(&sandbox, &source_path, spec, upload) {
+        fs::remove_dir_all(&sandbox).ok();
+        let reason = format!("Failed to stage source: {}", e);
+        return (TaskResult::failed(Status::Failed, &reason), None);
+    }
+
+    // Only paths that now exist in the sandbox are rewritten
+    let args = if spec.origin.is_empty() {
+        spec.args.clone()
+    } else {
+        rewrite_for_sandbox(&spec.args, &sandbox)
+    };
+    if !local_build && let Err(e) = check_forced_includes(&args, &sandbox) {
+        fs::remove_dir_all(&sandbox).ok();
+        fs::remove_file(&source_path).ok();
+        return (TaskResult::failed(Status::Failed, &e), None);
+    }
+
+    // EXECUTE THE COMPILER
+    // <cc> <args> -c <scratch>/file.c -o <scratch>/file.o [-MD -MF <scratch>/file.d]
+    let depfile_path = output_path.with_extension("d");
+    let mut command = Command::new(&spec.compiler);
+    command
+        .args(&args)
+        .arg("-c")
+        .arg(&source_path)
+        .arg("-o")
+        .arg(&output_path);
+    if spec.deps {
+        command.arg("-MD").arg("-MF").arg(&depfile_path);
+    }
+
+    let timeout = Duration::from_secs(spec.timeout_secs as u64);
+    let result = match run_with_timeout(command, timeout) {
+        Ok(None) => TaskResult::failed(
+            Status::TimedOut,
+            &format!("Compilation exceeded {} s and was killed", timeout.as_secs()),
+        ),
+        Ok(Some(out)) => TaskResult {
+            status: if out.status.success() { Status::Succeeded } else { Status::Failed },
+            stdout: String::from_utf8_lossy(&out.stdout).to_string(),
+            stderr: String::from_utf8_lossy(&out.stderr).to_string(),
+            deps: Vec::new(),
+        },
+        Err(e) => TaskResult::failed(Status::Failed, &e.to_string()), // Compiler likely not found
+    };
+    let result = if spec.deps && result.status == Status::Succeeded {
+        match depfile::read(&depfile_path) {
+            Ok(deps) => TaskResult {
+                deps: controller_deps(deps, &source_path, &sandbox),
+                ..result
+            },
+            Err(e) => TaskResult {
+                status: Status::Failed,
+                stderr: format!("{}Failed to read the dependency file: {}", result.stderr, e),
+                ..result
+            },
+        }
+    } else {
+        result
+    };
+
+    fs::remove_file(&depfile_path).ok();
+    fs::remove_file(&source_path).ok();
+    fs::remove_dir_all(&sandbox).ok();
+    if result.status == Status::Succeeded {
+        (result, Some(output_path))
+    } else {
+        fs::remove_file(&output_path).ok();
+        (result, None)
+    }
+}
+
+// Headers from the compile's depfile as the controller knows them: the
+// scratch copy of the source is left out, and files rebuilt in the sandbox
+// get back the paths they had on the client.
+fn controller_deps(deps: Vec<String>, source_path: &Path, sandbox: &Path) -> Vec<String> {
+    deps.into_iter()
+        .filter(|dep| Path::new(dep) != source_path)
+        .map(|dep| match Path::new(&dep).strip_prefix(sandbox) {
+            Ok(original) => Path::new("/").join(original).to_string_lossy().to_string(),
+            Err(_) => dep,
+        })
+        .collect()
+}
+
+// Send a TaskResult followed by its object file, which is removed once sent.
+fn send_result(stream: &mut impl FrameWrite, result: TaskResult, object: Option<PathBuf>) -> io::Result<()> {
+    let Some(object_path) = object else {
+        result.send(stream)?;
+        return send_bytes(stream, &[]);
+    };
+
+    let opened = File::open(&object_path).and_then(|file| Ok((file.metadata()?.len(), file)));
+    let sent = match opened {
+        Ok((size, mut file)) => {
+            result.send(stream)?;
+            send_body(stream, &mut file, size, 0, "")
+        }
+        Err(e) => {
+            let failed = TaskResult {
+                status: Status::Failed,
+                stderr: format!("Failed to read object file: {}", e),
+                ..result
+            };
+            failed.send(stream)?;
+            send_bytes(stream, &[])
+        }
+    };
+    fs::remove_file(&object_path).ok();
+    sent
+}
+
+// Run the compiler, killing it once `timeout` has passed. Returns None if it
+// had to be killed.
+fn run_with_timeout(mut command: Command, timeout: Duration) -> io::Result<Option<Output>> {
+    // The driver runs cc1 and as as processes of their own; a group lets a
+    // timeout take all of them down together
+    #[cfg(unix)]
+    std::os::unix::process::CommandExt::process_group(&mut command, 0);
+
+    // Compilers have no business with the server's secret
+    command.env_remove(LOCAL_WORKER_SECRET_VAR);
+    let mut child = command.stdout(Stdio::piped()).stderr(Stdio::piped()).spawn()?;
+
+    // Drain the pipes while waiting so a chatty compiler can't block on them
+    let stdout = child.stdout.take().map(|mut pipe| {
+        thread::spawn(move || {
+            let mut buf = Vec::new();
+            pipe.read_to_end(&mut buf).ok();
+            buf
+        })
+    });
+    let stderr = child.stderr.take().map(|mut pipe| {
+        thread::spawn(move || {
+            let mut buf = Vec::new();
+            pipe.read_to_end(&mut buf).ok();
+            buf
+        })
+    });
+
+    let deadline = Instant::now() + timeout;
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break Some(status);
+        }
+        if Instant::now() >= deadline {
+            kill_tree(&mut child);
+            child.wait()?;
+            break None;
+        }
+        thread::sleep(Duration::from_millis(20));
+    };
+
+    // Anything left holding the pipes open must not keep us waiting past
+    // the deadline; the reader threads finish on their own eventually
+    let Some(status) = status else {
+        return Ok(None);
+    };
+    let stdout = stdout.and_then(|h| h.join().ok()).unwrap_or_default();
+    let stderr = stderr.and_then(|h| h.join().ok()).unwrap_or_default();
+    Ok(Some(Output { status, stdout, stderr }))
+}
+
+#[cfg(unix)]
+fn kill_tree(child: &mut Child) {
+    // Negative pid: the whole process group the compiler leads
+    unsafe {
+        libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL);
+    }
+}
+
+#[cfg(not(unix))]
+fn kill_tree(child: &mut Child) {
+    child.kill().ok();
+}
+
+fn stage_files(sandbox: &Path, source_path: &Path, spec: &TaskDef, upload: &Path) -> io::Result<()> {
+    for (header_path, contents) in &spec.headers {
+        let local = sandbox_path(sandbox, header_path)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("unsafe header path {}", header_path)))?;
+        create_parent(&local)?;
+        fs::write(&local, contents)?;
+    }
+    create_parent(source_path)?;
+    fs::rename(upload, source_path)
+}
+
+fn create_parent(path: &Path) -> io::Result<()> {
+    match path.parent() {
+        Some(parent) => fs::create_dir_all(parent),
+        None => Ok(()),
+    }
 }